@@ -0,0 +1,658 @@
+use crossbeam_channel as chan;
+use log::*;
+
+use crate::devices::{Device, Probe};
+use crate::memory::{MemException::*, MemResult, Memory};
+
+use super::vic::Interrupt;
+
+/// Number of memory-to-peripheral channels
+pub const NUM_M2P_CHANNELS: usize = 10;
+
+/// Offsets of the M2P channel register blocks (in channel order)
+const M2P_CHANNEL_BASE: [u32; NUM_M2P_CHANNELS] = [
+    0x000, 0x040, 0x080, 0x0c0, 0x240, 0x200, 0x2c0, 0x280, 0x340, 0x300,
+];
+
+const M2P_INTERRUPTS: [Interrupt; NUM_M2P_CHANNELS] = [
+    Interrupt::DmaM2P0,
+    Interrupt::DmaM2P1,
+    Interrupt::DmaM2P2,
+    Interrupt::DmaM2P3,
+    Interrupt::DmaM2P4,
+    Interrupt::DmaM2P5,
+    Interrupt::DmaM2P6,
+    Interrupt::DmaM2P7,
+    Interrupt::DmaM2P8,
+    Interrupt::DmaM2P9,
+];
+
+// Control register bits
+const CONTROL_STALLINTEN: u32 = 1 << 0;
+const CONTROL_NFBINTEN: u32 = 1 << 1;
+const CONTROL_CHERRORINTEN: u32 = 1 << 3;
+const CONTROL_ENABLE: u32 = 1 << 4;
+const CONTROL_ICE: u32 = 1 << 6;
+
+// Interrupt / Status register bits
+const INT_STALL: u32 = 1 << 0;
+const INT_NFB: u32 = 1 << 1;
+const INT_CHERROR: u32 = 1 << 3;
+
+/// Peripherals which can be allocated to an M2P channel via PPALLOC
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Port {
+    I2s1,
+    I2s2,
+    Aac1,
+    Aac2,
+    Aac3,
+    I2s3,
+    Uart1,
+    Uart2,
+    Uart3,
+    Irda,
+}
+
+impl Port {
+    fn from_ppalloc(val: u32) -> Option<Port> {
+        use Port::*;
+        let port = match val & 0xf {
+            0 => I2s1,
+            1 => I2s2,
+            2 => Aac1,
+            3 => Aac2,
+            4 => Aac3,
+            5 => I2s3,
+            6 => Uart1,
+            7 => Uart2,
+            8 => Uart3,
+            9 => Irda,
+            _ => return None,
+        };
+        Some(port)
+    }
+}
+
+/// M2P channel state, as described in section 10.1.4.1 of the EP93xx User's
+/// Guide.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+    /// Channel disabled
+    Idle = 0,
+    /// Channel enabled, but has run out of buffers
+    Stall = 1,
+    /// Transferring to / from the current buffer, with no next buffer
+    On = 2,
+    /// Transferring to / from the current buffer, with a next buffer queued
+    Next = 3,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct Buffer {
+    maxcnt: u32,
+    base: u32,
+    current: u32,
+}
+
+impl Buffer {
+    fn remaining(&self) -> u32 {
+        self.maxcnt.saturating_sub(self.current - self.base)
+    }
+}
+
+#[derive(Debug)]
+struct Channel {
+    control: u32,
+    ppalloc: u32,
+    state: State,
+    error: bool,
+    buffers: [Buffer; 2],
+    // index of the buffer currently being transferred to / from
+    current: usize,
+    // index of the buffer register set the next buffer should be written to
+    next: usize,
+
+    interrupt: Interrupt,
+    int_asserted: bool,
+    clock_enabled: bool,
+}
+
+impl Channel {
+    fn new(interrupt: Interrupt) -> Channel {
+        Channel {
+            control: 0,
+            ppalloc: 0,
+            state: State::Idle,
+            error: false,
+            buffers: [Buffer::default(); 2],
+            current: 0,
+            next: 0,
+
+            interrupt,
+            int_asserted: false,
+            clock_enabled: false,
+        }
+    }
+
+    fn interrupt_status(&self) -> u32 {
+        let mut status = 0;
+        if self.state == State::Stall {
+            status |= INT_STALL;
+        }
+        if self.state == State::On {
+            status |= INT_NFB;
+        }
+        if self.error {
+            status |= INT_CHERROR;
+        }
+        status
+    }
+
+    fn masked_interrupt_status(&self) -> u32 {
+        let mut mask = 0;
+        if self.control & CONTROL_STALLINTEN != 0 {
+            mask |= INT_STALL;
+        }
+        if self.control & CONTROL_NFBINTEN != 0 {
+            mask |= INT_NFB;
+        }
+        if self.control & CONTROL_CHERRORINTEN != 0 {
+            mask |= INT_CHERROR;
+        }
+        self.interrupt_status() & mask
+    }
+
+    fn update_interrupt(&mut self, interrupt_bus: &chan::Sender<(Interrupt, bool)>) {
+        let asserted = self.masked_interrupt_status() != 0;
+        if asserted != self.int_asserted {
+            interrupt_bus.send((self.interrupt, asserted)).unwrap();
+            self.int_asserted = asserted;
+        }
+    }
+
+    /// Writing a buffer's base address hands it off to the channel.
+    fn queue_buffer(&mut self, index: usize) {
+        if index != self.next {
+            warn!(
+                "DMA buffer {} written, but the channel expected buffer {}",
+                index, self.next
+            );
+        }
+        self.buffers[index].current = self.buffers[index].base;
+
+        self.state = match self.state {
+            State::Idle => return,
+            State::Stall => {
+                self.current = index;
+                State::On
+            }
+            State::On => State::Next,
+            State::Next => {
+                warn!("DMA buffer written while both buffers were in use");
+                State::Next
+            }
+        };
+        self.next = index ^ 1;
+
+        // a buffer with MaxCnt = 0 has nothing to transfer
+        if self.state == State::On && self.buffers[self.current].remaining() == 0 {
+            self.buffer_done();
+        }
+    }
+
+    /// Retire the current buffer, moving on to the next one (if any). Empty
+    /// buffers are retired straight away.
+    fn buffer_done(&mut self) {
+        self.state = match self.state {
+            State::Next => {
+                self.current ^= 1;
+                State::On
+            }
+            _ => State::Stall,
+        };
+        if self.state == State::On && self.buffers[self.current].remaining() == 0 {
+            self.buffer_done();
+        }
+    }
+
+    /// The address of the next byte to transfer, if the channel is active.
+    fn transfer_addr(&self) -> Option<u32> {
+        if !self.clock_enabled {
+            return None;
+        }
+        let buf = &self.buffers[self.current];
+        match self.state {
+            State::On | State::Next if buf.remaining() != 0 => Some(buf.current),
+            _ => None,
+        }
+    }
+}
+
+/// Direction of an M2P channel's transfers.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Direction {
+    /// Memory to peripheral (i.e: tx)
+    MemToPeripheral,
+    /// Peripheral to memory (i.e: rx)
+    PeripheralToMem,
+}
+
+/// A pending single-byte transfer on an M2P channel.
+#[derive(Debug, Clone, Copy)]
+pub struct Transfer {
+    pub channel: usize,
+    pub port: Port,
+    pub direction: Direction,
+    pub addr: u32,
+}
+
+/// EP93xx DMA controller. Only the 10 memory-to-peripheral (M2P) channels
+/// are implemented.
+///
+/// The controller doesn't touch the bus itself. Instead, the system queries
+/// for pending transfers via [Dma::transfers], carries them out, and reports
+/// back using [Dma::transfer_done] or [Dma::transfer_error].
+///
+/// As described in section 10 of the EP93xx User's Guide
+#[derive(Debug)]
+pub struct Dma {
+    channels: Vec<Channel>,
+    arbitration: u32,
+
+    interrupt_bus: chan::Sender<(Interrupt, bool)>,
+}
+
+impl Dma {
+    /// Create a new DMA controller
+    pub fn new(interrupt_bus: chan::Sender<(Interrupt, bool)>) -> Dma {
+        Dma {
+            channels: M2P_INTERRUPTS.iter().map(|&i| Channel::new(i)).collect(),
+            arbitration: 0,
+
+            interrupt_bus,
+        }
+    }
+
+    /// Returns true if any channel is actively transferring data.
+    pub fn is_active(&self) -> bool {
+        self.channels.iter().any(|c| c.transfer_addr().is_some())
+    }
+
+    /// Enumerate the transfers the active channels are ready to carry out,
+    /// indexed by channel.
+    pub fn transfers(&self) -> [Option<Transfer>; NUM_M2P_CHANNELS] {
+        let mut transfers = [None; NUM_M2P_CHANNELS];
+        for (i, t) in transfers.iter_mut().enumerate() {
+            *t = self.transfer(i);
+        }
+        transfers
+    }
+
+    fn transfer(&self, channel: usize) -> Option<Transfer> {
+        let c = &self.channels[channel];
+        Some(Transfer {
+            channel,
+            port: Port::from_ppalloc(c.ppalloc)?,
+            // even channels transmit, odd channels receive
+            direction: if channel % 2 == 0 {
+                Direction::MemToPeripheral
+            } else {
+                Direction::PeripheralToMem
+            },
+            addr: c.transfer_addr()?,
+        })
+    }
+
+    /// Gate a channel's clock (as controlled by the DMA bits in the Syscon's
+    /// PwrCnt register). Channels are frozen while their clock is disabled.
+    pub fn set_clock_enabled(&mut self, channel: usize, enabled: bool) {
+        self.channels[channel].clock_enabled = enabled;
+    }
+
+    /// Advance the channel past a successfully transferred byte.
+    pub fn transfer_done(&mut self, channel: usize) {
+        let c = &mut self.channels[channel];
+        let buf = &mut c.buffers[c.current];
+        buf.current += 1;
+        if buf.remaining() == 0 {
+            trace!(
+                "DMA channel {} finished buffer {:#010x?}",
+                channel,
+                buf.base
+            );
+            c.buffer_done();
+        }
+        c.update_interrupt(&self.interrupt_bus);
+    }
+
+    /// Flag a bus error on the channel. Unless the channel is configured to
+    /// ignore errors, the channel is stalled.
+    pub fn transfer_error(&mut self, channel: usize) {
+        let c = &mut self.channels[channel];
+        c.error = true;
+        if c.control & CONTROL_ICE == 0 {
+            c.state = State::Stall;
+            c.update_interrupt(&self.interrupt_bus);
+        } else {
+            self.transfer_done(channel);
+        }
+    }
+
+    fn channel_offset(offset: u32) -> Option<(usize, u32)> {
+        let index = M2P_CHANNEL_BASE
+            .iter()
+            .position(|&base| (base..base + 0x40).contains(&offset))?;
+        Some((index, offset - M2P_CHANNEL_BASE[index]))
+    }
+
+    /// Accesses to a channel with its clock disabled are ignored (with reads
+    /// returning 0), as the channel's registers aren't being clocked.
+    fn check_clock_enabled(&self, channel: usize) -> MemResult<()> {
+        if self.channels[channel].clock_enabled {
+            Ok(())
+        } else {
+            Err(ContractViolation {
+                msg: format!(
+                    "Accessed DMA channel {} while its clock is disabled (check syscon PwrCnt)",
+                    channel
+                ),
+                severity: log::Level::Warn,
+                stub_val: Some(0),
+            })
+        }
+    }
+}
+
+impl Device for Dma {
+    fn kind(&self) -> &'static str {
+        "DMA"
+    }
+
+    fn probe(&self, offset: u32) -> Probe<'_> {
+        let reg = match offset {
+            0x380 => "DMAChArb",
+            0x3c0 => "DMAGlInt",
+            _ => match Dma::channel_offset(offset) {
+                Some((_, reg)) => match reg {
+                    0x00 => "Control",
+                    0x04 => "Interrupt",
+                    0x08 => "PPAlloc",
+                    0x0C => "Status",
+                    0x14 => "Remain",
+                    0x20 => "MaxCnt0",
+                    0x24 => "Base0",
+                    0x28 => "Current0",
+                    0x30 => "MaxCnt1",
+                    0x34 => "Base1",
+                    0x38 => "Current1",
+                    _ => return Probe::Unmapped,
+                },
+                None => return Probe::Unmapped,
+            },
+        };
+        Probe::Register(reg)
+    }
+}
+
+impl Memory for Dma {
+    fn r32(&mut self, offset: u32) -> MemResult<u32> {
+        match offset {
+            // channel arbitration
+            0x380 => Ok(self.arbitration),
+            // global interrupt
+            0x3c0 => Ok(self
+                .channels
+                .iter()
+                .enumerate()
+                .filter(|(_, c)| c.int_asserted)
+                .fold(0, |acc, (i, _)| acc | (1 << i))),
+            _ => {
+                let (index, reg) = match Dma::channel_offset(offset) {
+                    Some(v) => v,
+                    // M2M channels
+                    None => return Err(Unimplemented),
+                };
+                self.check_clock_enabled(index)?;
+
+                let c = &self.channels[index];
+                match reg {
+                    // control
+                    0x00 => Ok(c.control),
+                    // interrupt
+                    0x04 => Ok(c.masked_interrupt_status()),
+                    // peripheral port allocation
+                    0x08 => Ok(c.ppalloc),
+                    // status
+                    0x0C => Ok(c.interrupt_status() | (c.state as u32) << 4 | (c.next as u32) << 6),
+                    // remaining bytes in the current buffer
+                    0x14 => Ok(match c.transfer_addr() {
+                        Some(_) => c.buffers[c.current].remaining(),
+                        None => 0,
+                    }),
+                    // buffer 0
+                    0x20 => Ok(c.buffers[0].maxcnt),
+                    0x24 => Err(InvalidAccess),
+                    0x28 => Ok(c.buffers[0].current),
+                    // buffer 1
+                    0x30 => Ok(c.buffers[1].maxcnt),
+                    0x34 => Err(InvalidAccess),
+                    0x38 => Ok(c.buffers[1].current),
+                    _ => Err(Unexpected),
+                }
+            }
+        }
+    }
+
+    fn w32(&mut self, offset: u32, val: u32) -> MemResult<()> {
+        match offset {
+            // channel arbitration
+            0x380 => Ok(self.arbitration = val & 1),
+            // global interrupt
+            0x3c0 => Err(InvalidAccess),
+            _ => {
+                let (index, reg) = match Dma::channel_offset(offset) {
+                    Some(v) => v,
+                    // M2M channels
+                    None => return Err(Unimplemented),
+                };
+                self.check_clock_enabled(index)?;
+
+                let c = &mut self.channels[index];
+                let res = match reg {
+                    // control
+                    0x00 => {
+                        let was_enabled = c.control & CONTROL_ENABLE != 0;
+                        c.control = val & 0x7b;
+                        match (was_enabled, val & CONTROL_ENABLE != 0) {
+                            (false, true) => {
+                                if Port::from_ppalloc(c.ppalloc).is_none() {
+                                    warn!("DMA channel {} enabled with an invalid PPALLOC", index);
+                                }
+                                c.state = State::Stall;
+                                c.next = 0;
+                            }
+                            (true, false) => c.state = State::Idle,
+                            _ => {}
+                        }
+                        Ok(())
+                    }
+                    // interrupt (any write clears the channel error)
+                    0x04 => Ok(c.error = false),
+                    // peripheral port allocation
+                    0x08 => {
+                        if c.control & CONTROL_ENABLE != 0 {
+                            return Err(ContractViolation {
+                                msg: "Cannot change PPALLOC while the channel is enabled"
+                                    .to_string(),
+                                severity: log::Level::Error,
+                                stub_val: None,
+                            });
+                        }
+                        c.ppalloc = val & 0xf;
+                        match Port::from_ppalloc(c.ppalloc) {
+                            Some(Port::Uart1) | Some(Port::Uart2) | Some(Port::Uart3) => Ok(()),
+                            _ => Err(ContractViolation {
+                                msg: format!(
+                                    "DMA to/from {:?} is not implemented",
+                                    Port::from_ppalloc(c.ppalloc)
+                                ),
+                                severity: log::Level::Warn,
+                                stub_val: None,
+                            }),
+                        }
+                    }
+                    // status
+                    0x0C => Err(InvalidAccess),
+                    // remaining bytes in the current buffer
+                    0x14 => Err(InvalidAccess),
+                    // buffer 0
+                    0x20 => Ok(c.buffers[0].maxcnt = val & 0xffff),
+                    0x24 => Ok({
+                        c.buffers[0].base = val;
+                        c.queue_buffer(0)
+                    }),
+                    0x28 => Err(InvalidAccess),
+                    // buffer 1
+                    0x30 => Ok(c.buffers[1].maxcnt = val & 0xffff),
+                    0x34 => Ok({
+                        c.buffers[1].base = val;
+                        c.queue_buffer(1)
+                    }),
+                    0x38 => Err(InvalidAccess),
+                    _ => Err(Unexpected),
+                };
+                c.update_interrupt(&self.interrupt_bus);
+                res
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Create a controller with channel 0 enabled and allocated to UART1
+    fn dma() -> (Dma, chan::Receiver<(Interrupt, bool)>) {
+        let (interrupt_tx, interrupt_rx) = chan::unbounded();
+        let mut dma = Dma::new(interrupt_tx);
+        dma.set_clock_enabled(0, true);
+        dma.w32(0x08, 6).unwrap();
+        dma.w32(0x00, CONTROL_ENABLE | CONTROL_STALLINTEN | CONTROL_NFBINTEN)
+            .unwrap();
+        (dma, interrupt_rx)
+    }
+
+    fn state(dma: &mut Dma) -> u32 {
+        (dma.r32(0x0C).unwrap() >> 4) & 0x3
+    }
+
+    fn addr(dma: &Dma) -> Option<u32> {
+        dma.transfers()[0].map(|t| t.addr)
+    }
+
+    #[test]
+    fn buffer_state_machine() {
+        let (mut dma, interrupts) = dma();
+        assert_eq!(state(&mut dma), State::Stall as u32);
+        assert_eq!(interrupts.try_recv(), Ok((Interrupt::DmaM2P0, true)));
+        assert!(!dma.is_active());
+
+        // buffer 0 (2 bytes)
+        dma.w32(0x20, 2).unwrap();
+        dma.w32(0x24, 0x1000).unwrap();
+        assert_eq!(state(&mut dma), State::On as u32);
+        let t = dma.transfers()[0].unwrap();
+        assert_eq!(t.port, Port::Uart1);
+        assert_eq!(t.direction, Direction::MemToPeripheral);
+        assert_eq!(t.addr, 0x1000);
+
+        // buffer 1 (1 byte), which clears the NFB interrupt
+        dma.w32(0x30, 1).unwrap();
+        dma.w32(0x34, 0x2000).unwrap();
+        assert_eq!(state(&mut dma), State::Next as u32);
+        assert_eq!(interrupts.try_recv(), Ok((Interrupt::DmaM2P0, false)));
+
+        dma.transfer_done(0);
+        assert_eq!(addr(&dma), Some(0x1001));
+        assert_eq!(dma.r32(0x14).unwrap(), 1);
+        dma.transfer_done(0);
+        assert_eq!(state(&mut dma), State::On as u32);
+        assert_eq!(addr(&dma), Some(0x2000));
+        assert_eq!(interrupts.try_recv(), Ok((Interrupt::DmaM2P0, true)));
+
+        dma.transfer_done(0);
+        assert_eq!(state(&mut dma), State::Stall as u32);
+        assert!(!dma.is_active());
+        assert!(interrupts.try_recv().is_err());
+
+        // disabling the channel idles it
+        dma.w32(0x00, 0).unwrap();
+        assert_eq!(state(&mut dma), State::Idle as u32);
+        assert_eq!(interrupts.try_recv(), Ok((Interrupt::DmaM2P0, false)));
+    }
+
+    #[test]
+    fn empty_buffers() {
+        let (mut dma, _interrupts) = dma();
+
+        // a buffer with MaxCnt = 0 is done as soon as it's queued
+        dma.w32(0x20, 0).unwrap();
+        dma.w32(0x24, 0x1000).unwrap();
+        assert_eq!(state(&mut dma), State::Stall as u32);
+        assert!(!dma.is_active());
+
+        // ...or as soon as the buffer before it is done
+        dma.w32(0x30, 1).unwrap();
+        dma.w32(0x34, 0x2000).unwrap();
+        dma.w32(0x20, 0).unwrap();
+        dma.w32(0x24, 0x3000).unwrap();
+        assert_eq!(state(&mut dma), State::Next as u32);
+        dma.transfer_done(0);
+        assert_eq!(state(&mut dma), State::Stall as u32);
+        assert!(!dma.is_active());
+    }
+
+    #[test]
+    fn transfer_errors() {
+        let (mut dma, _interrupts) = dma();
+        dma.w32(0x20, 2).unwrap();
+        dma.w32(0x24, 0x1000).unwrap();
+
+        // errors stall the channel...
+        dma.transfer_error(0);
+        assert_eq!(state(&mut dma), State::Stall as u32);
+        assert_ne!(dma.r32(0x0C).unwrap() & INT_CHERROR, 0);
+        dma.w32(0x04, 0).unwrap();
+        assert_eq!(dma.r32(0x0C).unwrap() & INT_CHERROR, 0);
+
+        // ...unless they're ignored
+        dma.w32(0x00, CONTROL_ENABLE | CONTROL_ICE).unwrap();
+        dma.w32(0x20, 2).unwrap();
+        dma.w32(0x24, 0x1000).unwrap();
+        dma.transfer_error(0);
+        assert_eq!(addr(&dma), Some(0x1001));
+    }
+
+    #[test]
+    fn clock_gating() {
+        let (mut dma, _interrupts) = dma();
+        dma.w32(0x20, 2).unwrap();
+        dma.w32(0x24, 0x1000).unwrap();
+
+        // gated channels are frozen, and their registers ignored
+        dma.set_clock_enabled(0, false);
+        assert!(!dma.is_active());
+        assert!(matches!(
+            dma.r32(0x00),
+            Err(ContractViolation {
+                stub_val: Some(0),
+                ..
+            })
+        ));
+
+        dma.set_clock_enabled(0, true);
+        assert_eq!(addr(&dma), Some(0x1000));
+    }
+}
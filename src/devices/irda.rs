@@ -0,0 +1,47 @@
+use crate::devices::{Device, Probe};
+use crate::memory::{MemException::*, MemResult, Memory};
+
+/// Stub for the EP93xx's IrDA controller.
+///
+/// The TS-7200 doesn't wire up an IR transceiver, so any access to the
+/// controller is almost certainly a bug (e.g: a typo'd UART base address).
+/// Reads return 0, and writes are ignored.
+#[derive(Debug, Default)]
+pub struct Irda {}
+
+impl Irda {
+    /// Create a new IrDA stub
+    pub fn new() -> Irda {
+        Irda {}
+    }
+
+    fn unsupported(stub_val: Option<u32>) -> MemResult<u32> {
+        Err(ContractViolation {
+            msg: "The IrDA controller isn't wired up on the TS-7200 (did you mean to access \
+                  a UART?)"
+                .to_string(),
+            severity: log::Level::Warn,
+            stub_val,
+        })
+    }
+}
+
+impl Device for Irda {
+    fn kind(&self) -> &'static str {
+        "IrDA"
+    }
+
+    fn probe(&self, _offset: u32) -> Probe<'_> {
+        Probe::Unmapped
+    }
+}
+
+impl Memory for Irda {
+    fn r32(&mut self, _offset: u32) -> MemResult<u32> {
+        Irda::unsupported(Some(0))
+    }
+
+    fn w32(&mut self, _offset: u32, _val: u32) -> MemResult<()> {
+        Irda::unsupported(None).map(drop)
+    }
+}
@@ -3,12 +3,16 @@
     clippy::match_bool // can make things more clear at times
 )]
 
+pub mod dma;
+pub mod irda;
 pub mod ram;
 pub mod syscon;
 pub mod timer;
 pub mod uart;
 pub mod vic;
 
+pub use dma::Dma;
+pub use irda::Irda;
 pub use ram::Ram;
 pub use syscon::Syscon;
 pub use timer::Timer;
@@ -83,6 +87,9 @@ pub enum Probe<'a> {
     },
     /// Leaf node representing a register.
     Register(&'a str),
+    /// Leaf node representing a register, alongside a description of its
+    /// current contents.
+    RegisterState { name: &'a str, state: String },
     /// Unmapped memory.
     Unmapped,
 }
@@ -102,6 +109,7 @@ impl<'a> std::fmt::Display for Probe<'a> {
                 }
             }
             Probe::Register(name) => write!(f, "{}", name)?,
+            Probe::RegisterState { name, state } => write!(f, "{} {}", name, state)?,
             Probe::Unmapped => write!(f, "<unmapped>")?,
         }
 
@@ -1,6 +1,129 @@
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crossbeam_channel::{self as chan, select};
+use log::*;
+
 use crate::devices::{Device, Probe};
 use crate::memory::{MemException::*, MemResult, Memory};
 
+use super::dma;
+use super::vic::Interrupt;
+
+/// Frequency of the external XTALI oscillator, which drives both PLLs.
+const XTALI_HZ: u64 = 14_745_600;
+
+// ClkSet1 / ClkSet2 values as configured by Redboot on the TS-7200 (i.e:
+// FCLK = 200 MHz, HCLK = 100 MHz, PCLK = 50 MHz, and a 192 MHz PLL2)
+const HLE_CLKSET1: u32 = 0x02a4_f0c7;
+const HLE_CLKSET2: u32 = 0x300d_c317;
+
+// Identification values of the EP9302 (rev E2) found on the TS-7200
+const CHIP_REV: u32 = 7;
+const CHIP_ID: u32 = (CHIP_REV << 28) | 0x9213;
+// Rev E2, booting from 16-bit asynchronous flash
+const HLE_SYSCFG: u32 = (CHIP_REV << 28) | 0x01;
+
+/// PwrCnt UART baud clock select bit (UARTCLK = XTALI when set, XTALI / 2
+/// otherwise)
+const PWRCNT_UARTBAUD: u32 = 1 << 29;
+
+/// PwrCnt DMA M2P channel clock enable bits (in channel order). Note that
+/// the bits for each pair of channels are swapped (see page 5-21).
+const PWRCNT_DMA_M2P: [u32; dma::NUM_M2P_CHANNELS] = [
+    1 << 17,
+    1 << 16,
+    1 << 19,
+    1 << 18,
+    1 << 21,
+    1 << 20,
+    1 << 23,
+    1 << 22,
+    1 << 25,
+    1 << 24,
+];
+
+// DeviceCfg UART enable bits
+const DEVICECFG_U1EN: u32 = 1 << 18;
+const DEVICECFG_U2EN: u32 = 1 << 20;
+
+/// Decode the output frequency of one of the EP93xx's PLLs from the PLL
+/// configuration bits shared by ClkSet1 and ClkSet2 (see page 5-16)
+fn pll_hz(cfg: u32) -> u64 {
+    let x1fbd = ((cfg >> 11) & 0x1f) as u64 + 1;
+    let x2fbd = ((cfg >> 5) & 0x3f) as u64 + 1;
+    let x2ipd = (cfg & 0x1f) as u64 + 1;
+    let ps = (cfg >> 16) & 0x3;
+    (XTALI_HZ * x1fbd * x2fbd / x2ipd) >> ps
+}
+
+/// Frequency of the system tick interrupt (TINTR)
+const TICK_HZ: u64 = 64;
+
+fn spawn_tick_thread(
+    interrupt_bus: chan::Sender<(Interrupt, bool)>,
+) -> (JoinHandle<()>, chan::Sender<()>) {
+    let (exit_tx, exit_rx) = chan::bounded(1);
+    let thread = move || {
+        let period = Duration::from_nanos(1_000_000_000 / TICK_HZ);
+        let mut next = Instant::now() + period;
+        loop {
+            select! {
+                // the Syscon was dropped
+                recv(exit_rx) -> _ => return,
+                default(next.saturating_duration_since(Instant::now())) => {}
+            }
+            next += period;
+
+            // The tick is free-running, and is only ever cleared via TEOI
+            if interrupt_bus.send((Interrupt::Tintr, true)).is_err() {
+                // Receiver exited
+                return;
+            }
+        }
+    };
+
+    let handle = thread::Builder::new()
+        .name("syscon | 64Hz Tick".to_string())
+        .spawn(thread)
+        .unwrap();
+
+    (handle, exit_tx)
+}
+
+/// EP93xx system clock frequencies, as configured via ClkSet1.
+#[derive(Debug, Clone, Copy)]
+pub struct Clocks {
+    /// Processor clock
+    pub fclk_hz: u64,
+    /// AHB bus clock
+    pub hclk_hz: u64,
+    /// APB bus clock
+    pub pclk_hz: u64,
+}
+
+impl Clocks {
+    fn from_clkset1(clkset1: u32) -> Clocks {
+        const FCLK_DIV: [u64; 8] = [1, 2, 4, 8, 16, 1, 1, 1];
+        const HCLK_DIV: [u64; 8] = [1, 2, 4, 5, 6, 8, 16, 32];
+        const PCLK_DIV: [u64; 4] = [1, 2, 4, 8];
+
+        // PLL1 is bypassed unless nBYP1 is set
+        let pll1_hz = if clkset1 & (1 << 23) != 0 {
+            pll_hz(clkset1)
+        } else {
+            XTALI_HZ
+        };
+
+        let hclk_hz = pll1_hz / HCLK_DIV[((clkset1 >> 20) & 0x7) as usize];
+        Clocks {
+            fclk_hz: pll1_hz / FCLK_DIV[((clkset1 >> 25) & 0x7) as usize],
+            hclk_hz,
+            pclk_hz: hclk_hz / PCLK_DIV[((clkset1 >> 18) & 0x3) as usize],
+        }
+    }
+}
+
 /// EP9302 Power States (see page 5-10)
 #[derive(Debug, Clone, Copy)]
 pub enum PowerState {
@@ -15,23 +138,109 @@ pub enum PowerState {
 #[derive(Debug)]
 pub struct Syscon {
     scratch_reg: [u32; 2],
+    pwr_cnt: u32,
+    clkset: [u32; 2],
+    apb_wait: u32,
     device_cfg: u32,
+    sys_cfg: u32,
     is_locked: bool,
     power_state: PowerState,
+    // set whenever a register that other devices depend on is changed
+    clk_cfg_changed: bool,
+
+    interrupt_bus: chan::Sender<(Interrupt, bool)>,
+    // must be optional, as `.join()` can only be called on an owned JoinHandle
+    tick_thread: Option<JoinHandle<()>>,
+    tick_exit: chan::Sender<()>,
+}
+
+impl Drop for Syscon {
+    fn drop(&mut self) {
+        let _ = self.tick_exit.try_send(());
+        if let Some(thread) = self.tick_thread.take() {
+            if thread.join().is_err() {
+                error!("syscon tick thread panicked");
+            }
+        }
+    }
 }
 
 impl Syscon {
     /// Create a new System Controller
-    pub fn new_hle() -> Syscon {
-        Syscon {
+    pub fn new_hle(interrupt_bus: chan::Sender<(Interrupt, bool)>) -> Syscon {
+        let (tick_thread, tick_exit) = spawn_tick_thread(interrupt_bus.clone());
+        // registers are initialized by `reset_hle`
+        let mut syscon = Syscon {
             scratch_reg: [0, 0],
-            // Enabled Bits: GonK CPENA U2EN U1EN HonIDE GonIDE EonIDE
-            device_cfg: 0x0894_0d00, // hardware validated
+            pwr_cnt: 0,
+            clkset: [0, 0],
+            apb_wait: 0,
+            device_cfg: 0,
+            sys_cfg: 0,
             is_locked: true,
             power_state: PowerState::Run,
+            clk_cfg_changed: true,
+
+            interrupt_bus,
+            tick_thread: Some(tick_thread),
+            tick_exit,
+        };
+        syscon.reset_hle();
+        syscon
+    }
+
+    /// Reset the System Controller's registers to the state they're left in
+    /// by the bootloader. The 64Hz tick thread is left running.
+    pub fn reset_hle(&mut self) {
+        self.scratch_reg = [0, 0];
+        // No DMA clocks enabled, UARTBAUD = 0 (7.3728 MHz)
+        self.pwr_cnt = 0;
+        self.clkset = [HLE_CLKSET1, HLE_CLKSET2];
+        self.apb_wait = 0;
+        // Enabled Bits: GonK CPENA U2EN U1EN HonIDE GonIDE EonIDE
+        self.device_cfg = 0x0894_0d00;
+        self.sys_cfg = HLE_SYSCFG;
+        self.is_locked = true;
+        self.power_state = PowerState::Run;
+        self.clk_cfg_changed = true;
+    }
+
+    /// Query the current system clock frequencies.
+    pub fn clocks(&self) -> Clocks {
+        Clocks::from_clkset1(self.clkset[0])
+    }
+
+    /// Query the frequency of the UARTs' reference clock (UARTCLK).
+    pub fn uartclk_hz(&self) -> u64 {
+        if self.pwr_cnt & PWRCNT_UARTBAUD != 0 {
+            XTALI_HZ
+        } else {
+            XTALI_HZ / 2
+        }
+    }
+
+    /// Check if the specified UART has its clock enabled. Returns `None` for
+    /// UARTs the EP9302 doesn't have (i.e: the virtual UART3), as there's no
+    /// DeviceCfg bit controlling them.
+    pub fn uart_enabled(&self, uart: usize) -> Option<bool> {
+        match uart {
+            1 => Some(self.device_cfg & DEVICECFG_U1EN != 0),
+            2 => Some(self.device_cfg & DEVICECFG_U2EN != 0),
+            _ => None,
         }
     }
 
+    /// Check if the specified DMA M2P channel has its clock enabled.
+    pub fn dma_m2p_enabled(&self, channel: usize) -> bool {
+        self.pwr_cnt & PWRCNT_DMA_M2P[channel] != 0
+    }
+
+    /// Returns `true` if any clock configuration other devices depend on has
+    /// changed since the last call to this method.
+    pub fn take_clk_cfg_changed(&mut self) -> bool {
+        std::mem::replace(&mut self.clk_cfg_changed, false)
+    }
+
     /// Query the current [`PowerState`] of the system.
     pub fn power_state(&self) -> PowerState {
         self.power_state
@@ -81,8 +290,9 @@ impl Device for Syscon {
 impl Memory for Syscon {
     fn r32(&mut self, offset: u32) -> MemResult<u32> {
         match offset {
-            0x00 => Err(Unimplemented),
-            0x04 => Err(Unimplemented),
+            // No wake-up / reset flags set after a clean Redboot boot
+            0x00 => Ok(0),
+            0x04 => Ok(self.pwr_cnt),
             0x08 => {
                 if self.device_cfg & 1 == 1 {
                     self.power_state = PowerState::Halt;
@@ -108,22 +318,22 @@ impl Memory for Syscon {
                     })
                 }
             }
-            0x18 => Err(Unimplemented),
+            0x18 => Err(InvalidAccess),
             0x1C => Err(Unimplemented),
-            0x20 => Err(Unimplemented),
-            0x24 => Err(Unimplemented),
+            0x20 => Ok(self.clkset[0]),
+            0x24 => Ok(self.clkset[1]),
             0x40 => Ok(self.scratch_reg[0]),
             0x44 => Ok(self.scratch_reg[1]),
-            0x50 => Err(Unimplemented),
+            0x50 => Ok(self.apb_wait),
             0x54 => Err(Unimplemented),
-            0x58 => Err(Unimplemented),
+            0x58 => Err(InvalidAccess),
             0x80 => Ok(self.device_cfg),
             0x84 => Err(Unimplemented),
             0x88 => Err(Unimplemented),
             0x8C => Err(Unimplemented),
             0x90 => Err(Unimplemented),
-            0x94 => Err(Unimplemented),
-            0x9C => Err(Unimplemented),
+            0x94 => Ok(CHIP_ID),
+            0x9C => Ok(self.sys_cfg),
             0xC0 => {
                 if self.is_locked {
                     Ok(0x00)
@@ -150,26 +360,46 @@ impl Memory for Syscon {
         }
 
         match offset {
-            0x00 => Err(Unimplemented),
-            0x04 => Err(Unimplemented),
+            0x00 => Err(InvalidAccess),
+            0x04 => {
+                self.pwr_cnt = val;
+                self.clk_cfg_changed = true;
+                Ok(())
+            }
             0x08 => Err(InvalidAccess),
             0x0C => Err(InvalidAccess),
-            0x18 => Err(Unimplemented),
+            // Any write clears the 64Hz tick interrupt
+            0x18 => Ok(self.interrupt_bus.send((Interrupt::Tintr, false)).unwrap()),
             0x1C => Err(Unimplemented),
-            0x20 => Err(Unimplemented),
-            0x24 => Err(Unimplemented),
+            0x20 => {
+                self.clkset[0] = val;
+                self.clk_cfg_changed = true;
+                let clocks = self.clocks();
+                info!(
+                    "ClkSet1 updated: FCLK = {} Hz, HCLK = {} Hz, PCLK = {} Hz",
+                    clocks.fclk_hz, clocks.hclk_hz, clocks.pclk_hz
+                );
+                Ok(())
+            }
+            0x24 => Ok(self.clkset[1] = val),
             0x40 => Ok(self.scratch_reg[0] = val),
             0x44 => Ok(self.scratch_reg[1] = val),
-            0x50 => Err(Unimplemented),
+            0x50 => Ok(self.apb_wait = val),
             0x54 => Err(Unimplemented),
-            0x58 => Err(Unimplemented),
-            0x80 => Ok(self.device_cfg = val),
+            // The boot ROM is never mapped in HLE mode, so there's nothing to clear
+            0x58 => Ok(()),
+            0x80 => {
+                self.device_cfg = val;
+                self.clk_cfg_changed = true;
+                Ok(())
+            }
             0x84 => Err(Unimplemented),
             0x88 => Err(Unimplemented),
             0x8C => Err(Unimplemented),
             0x90 => Err(Unimplemented),
-            0x94 => Err(Unimplemented),
-            0x9C => Err(Unimplemented),
+            0x94 => Err(InvalidAccess),
+            // Only the boot configuration latched from the hardware pins lives here
+            0x9C => Err(InvalidAccess),
             0xC0 => {
                 if val == 0xAA {
                     Ok(self.is_locked = false)
@@ -185,3 +415,26 @@ impl Memory for Syscon {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hle_clocks() {
+        // PLL1 = 14.7456 MHz * 31 * 7 / 8 ~= 400 MHz
+        let clocks = Clocks::from_clkset1(HLE_CLKSET1);
+        assert_eq!(clocks.fclk_hz, 199_987_200);
+        assert_eq!(clocks.hclk_hz, 99_993_600);
+        assert_eq!(clocks.pclk_hz, 49_996_800);
+    }
+
+    #[test]
+    fn bypassed_pll() {
+        // with nBYP1 clear, everything runs straight off XTALI
+        let clocks = Clocks::from_clkset1(HLE_CLKSET1 & !(1 << 23));
+        assert_eq!(clocks.fclk_hz, XTALI_HZ / 2);
+        assert_eq!(clocks.hclk_hz, XTALI_HZ / 4);
+        assert_eq!(clocks.pclk_hz, XTALI_HZ / 8);
+    }
+}
@@ -3,10 +3,18 @@
 use std::error::Error as StdError;
 use std::fmt::{self, Display};
 use std::fs;
-use std::net::TcpStream;
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::net::UnixStream;
 use std::str::FromStr;
 
-use super::{iothreads, ReaderTask, Uart, WriterTask};
+use crossbeam_channel as chan;
+
+use crate::devices::Device;
+
+use super::{
+    iothreads, CtsModel, HostCmd, Keymap, LineFormat, ModemLine, Parity, PeerHandle, ReaderTask,
+    Uart, WriterTask,
+};
 
 pub enum UartCfg {
     /// none
@@ -20,12 +28,38 @@ pub enum UartCfg {
     Stdio,
     /// tcp:[host]:port
     Tcp { host: String, port: u16 },
+    /// tcp:[host]:port,server[,wait]
+    TcpServer { host: String, port: u16, wait: bool },
+    /// pty
+    Pty,
+    /// unix:/path/to/sock
+    Unix { path: String },
+    /// unix:/path/to/sock,server[,wait]
+    UnixServer { path: String, wait: bool },
+    /// script:/path/to/script
+    Script { path: String },
+    /// marklin[:/path/to/timeline]
+    Marklin { timeline: Option<String> },
+    /// in:<cfg>,out:<cfg>
+    Split {
+        input: Box<UartCfg>,
+        output: Box<UartCfg>,
+    },
+    /// <cfg>,tee=<cfg>[,tee=<cfg>...]
+    Tee {
+        primary: Box<UartCfg>,
+        tees: Vec<UartCfg>,
+    },
 }
 
 #[derive(Debug)]
 pub enum UartCfgError {
     BadFile(std::io::Error),
     BadTcp(std::io::Error),
+    BadPty(std::io::Error),
+    BadUnix(std::io::Error),
+    BadScript(std::io::Error),
+    BadTimeline(std::io::Error),
 }
 
 impl Display for UartCfgError {
@@ -33,22 +67,124 @@ impl Display for UartCfgError {
         match self {
             UartCfgError::BadFile(e) => write!(f, "Could not open file: {}", e),
             UartCfgError::BadTcp(e) => write!(f, "Could not open tcp: {}", e),
+            UartCfgError::BadPty(e) => write!(f, "Could not open pty: {}", e),
+            UartCfgError::BadUnix(e) => write!(f, "Could not open unix socket: {}", e),
+            UartCfgError::BadScript(e) => write!(f, "Could not load script: {}", e),
+            UartCfgError::BadTimeline(e) => write!(f, "Could not load sensor timeline: {}", e),
         }
     }
 }
 impl StdError for UartCfgError {}
 
 impl UartCfg {
-    /// Apply uart configuration to the specified uart device
-    pub fn apply(&self, uart: &mut Uart) -> Result<(), UartCfgError> {
-        uart.install_io_tasks(|tx, rx| match self {
+    /// Apply uart configuration to the specified uart device while the system
+    /// is running, replacing any existing backend. Any resulting changes to
+    /// the modem lines are flagged to the guest.
+    ///
+    /// The existing backend is only detached once the new one is up and
+    /// running, so it's left in place if the new configuration fails.
+    ///
+    /// NOTE: stdio can't be attached at runtime, so only the system hooks in
+    /// `stdio` (e.g: `host_cmds`) are used.
+    pub fn apply(&self, uart: &mut Uart, stdio: &StdioCfg) -> Result<(), UartCfgError> {
+        self.attach(uart, stdio, true)
+    }
+
+    /// Apply the initial uart configuration to the specified uart device
+    /// (i.e: before the guest starts running), configuring any stdio backend
+    /// using `stdio`. The modem lines are set up without raising their delta
+    /// bits.
+    pub fn apply_with_stdio(&self, uart: &mut Uart, stdio: &StdioCfg) -> Result<(), UartCfgError> {
+        self.attach(uart, stdio, false)
+    }
+
+    fn attach(&self, uart: &mut Uart, stdio: &StdioCfg, hotplug: bool) -> Result<(), UartCfgError> {
+        let peer = uart.peer_handle();
+        let label = uart.label().unwrap_or_default().to_string();
+
+        let (old_reader, old_writer) =
+            uart.install_io_tasks(|tx, rx| self.spawn(&label, Some(peer.clone()), stdio, tx, rx))?;
+        if let Some(task) = old_reader {
+            task.cancel();
+        }
+        if let Some(task) = old_writer {
+            task.cancel();
+        }
+
+        // Any connected backend is treated as a peer which is present and ready.
+        // Socket servers only assert DCD while a client is connected (which is
+        // only the case at this point if they waited for one).
+        let connected = !matches!(self.primary(), UartCfg::None);
+        let carrier = match self.primary() {
+            UartCfg::None => false,
+            UartCfg::TcpServer { wait, .. } | UartCfg::UnixServer { wait, .. } => *wait,
+            _ => true,
+        };
+        for &(line, asserted) in &[(ModemLine::Dsr, connected), (ModemLine::Dcd, carrier)] {
+            if hotplug {
+                peer.set_modem_line(line, asserted);
+            } else {
+                peer.init_modem_line(line, asserted);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Check if the backend (or any of its constituent backends) uses stdio.
+    pub fn uses_stdio(&self) -> bool {
+        match self {
+            UartCfg::Stdio => true,
+            UartCfg::Split { input, output } => input.uses_stdio() || output.uses_stdio(),
+            UartCfg::Tee { primary, tees } => {
+                primary.uses_stdio() || tees.iter().any(UartCfg::uses_stdio)
+            }
+            _ => false,
+        }
+    }
+
+    /// Check if the backend (or any of its constituent backends) blocks until
+    /// a client connects to it.
+    pub fn waits_for_client(&self) -> bool {
+        match self {
+            UartCfg::TcpServer { wait, .. } | UartCfg::UnixServer { wait, .. } => *wait,
+            UartCfg::Split { input, output } => {
+                input.waits_for_client() || output.waits_for_client()
+            }
+            UartCfg::Tee { primary, tees } => {
+                primary.waits_for_client() || tees.iter().any(UartCfg::waits_for_client)
+            }
+            _ => false,
+        }
+    }
+
+    /// The backend which represents the device on the other end of the wire
+    /// (i.e: the one which drives the UART's modem lines).
+    fn primary(&self) -> &UartCfg {
+        match self {
+            UartCfg::Split { input, .. } => input.primary(),
+            UartCfg::Tee { primary, .. } => primary.primary(),
+            _ => self,
+        }
+    }
+
+    /// Spawn the reader/writer tasks for the backend. Only backends passed a
+    /// `peer` may update the UART's modem lines.
+    fn spawn(
+        &self,
+        label: &str,
+        peer: Option<PeerHandle>,
+        stdio: &StdioCfg,
+        tx: chan::Sender<u8>,
+        rx: chan::Receiver<u8>,
+    ) -> Result<(Option<ReaderTask>, Option<WriterTask>), UartCfgError> {
+        match self {
             UartCfg::None => Ok((None, None)),
             UartCfg::File { in_path, out_path } => {
                 let in_writer = match in_path {
                     Some(in_path) => {
                         let in_file = fs::File::open(&in_path).map_err(UartCfgError::BadFile)?;
-                        let in_thread = iothreads::reader_to_chan(in_path.to_string(), in_file, tx);
-                        Some(ReaderTask::new(in_thread))
+                        Some(iothreads::reader_to_chan(in_path.to_string(), in_file, tx))
                     }
                     None => None,
                 };
@@ -59,33 +195,270 @@ impl UartCfg {
                         .append(true)
                         .open(&out_path)
                         .map_err(UartCfgError::BadFile)?;
-                    let out_thread = iothreads::writer_to_chan(out_path.to_string(), out_file, rx);
-                    Some(WriterTask::new(out_thread))
+                    Some(iothreads::writer_to_chan(
+                        out_path.to_string(),
+                        out_file,
+                        rx,
+                    ))
                 };
 
                 Ok((in_writer, out_writer))
             }
             UartCfg::Stdio => {
-                let (in_thread, out_thread) = iothreads::stdio_to_chans(tx, rx);
-                Ok((
-                    Some(ReaderTask::new(in_thread)),
-                    Some(WriterTask::new(out_thread)),
-                ))
+                let (in_task, out_task) = iothreads::stdio_to_chans(stdio.clone(), tx, rx);
+                Ok((Some(in_task), Some(out_task)))
             }
             UartCfg::Tcp { host, port } => {
                 let addr = format!("{}:{}", host, port);
                 let in_stream = TcpStream::connect(&addr).map_err(UartCfgError::BadTcp)?;
                 let out_stream = in_stream.try_clone().expect("could not clone TcpStream");
 
-                let in_thread = iothreads::reader_to_chan(addr.clone(), in_stream, tx);
-                let out_thread = iothreads::writer_to_chan(addr, out_stream, rx);
-                Ok((
-                    Some(ReaderTask::new(in_thread)),
-                    Some(WriterTask::new(out_thread)),
-                ))
+                let in_task = iothreads::reader_to_chan(addr.clone(), in_stream, tx);
+                let out_task = iothreads::writer_to_chan(addr, out_stream, rx);
+                Ok((Some(in_task), Some(out_task)))
             }
-        })
-        .map(drop)
+            UartCfg::TcpServer { host, port, wait } => {
+                let addr = format!("{}:{}", host, port);
+                let listener = TcpListener::bind(&addr).map_err(UartCfgError::BadTcp)?;
+                let (in_task, out_task) =
+                    serve(addr, listener, *wait, peer, tx, rx).map_err(UartCfgError::BadTcp)?;
+                Ok((Some(in_task), Some(out_task)))
+            }
+            UartCfg::Unix { path } => {
+                let in_stream = UnixStream::connect(&path).map_err(UartCfgError::BadUnix)?;
+                let out_stream = in_stream.try_clone().expect("could not clone UnixStream");
+
+                let in_task = iothreads::reader_to_chan(path.clone(), in_stream, tx);
+                let out_task = iothreads::writer_to_chan(path.clone(), out_stream, rx);
+                Ok((Some(in_task), Some(out_task)))
+            }
+            UartCfg::UnixServer { path, wait } => {
+                let listener =
+                    iothreads::UnixSocketListener::bind(path).map_err(UartCfgError::BadUnix)?;
+                let (in_task, out_task) = serve(path.clone(), listener, *wait, peer, tx, rx)
+                    .map_err(UartCfgError::BadUnix)?;
+                Ok((Some(in_task), Some(out_task)))
+            }
+            UartCfg::Pty => {
+                let (master, path) = iothreads::open_pty().map_err(UartCfgError::BadPty)?;
+                let out_master = master.try_clone_writer().map_err(UartCfgError::BadPty)?;
+                eprintln!("{} connected to pty {}", label, path);
+
+                let in_task = iothreads::reader_to_chan(path.clone(), master, tx);
+                let out_task = iothreads::writer_to_chan(path, out_master, rx);
+                Ok((Some(in_task), Some(out_task)))
+            }
+            UartCfg::Script { path } => {
+                let src = fs::read_to_string(&path).map_err(UartCfgError::BadScript)?;
+                let script = iothreads::Script::parse(&src).map_err(|e| {
+                    UartCfgError::BadScript(std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+                })?;
+
+                let host_cmds = match &stdio.host_cmds {
+                    Some(host_cmds) => host_cmds.clone(),
+                    _ => {
+                        return Err(UartCfgError::BadScript(std::io::Error::new(
+                            std::io::ErrorKind::InvalidInput,
+                            "scripts can't be run without a system to control",
+                        )))
+                    }
+                };
+
+                let task =
+                    iothreads::script_to_chans(path.clone(), script, peer, host_cmds, tx, rx);
+                Ok((Some(task), None))
+            }
+            UartCfg::Marklin { timeline } => {
+                let timeline = match timeline {
+                    Some(path) => {
+                        let src = fs::read_to_string(&path).map_err(UartCfgError::BadTimeline)?;
+                        iothreads::Timeline::parse(&src).map_err(|e| {
+                            UartCfgError::BadTimeline(std::io::Error::new(
+                                std::io::ErrorKind::InvalidData,
+                                e,
+                            ))
+                        })?
+                    }
+                    None => iothreads::Timeline::default(),
+                };
+
+                // the 6051 talks 8N2 @ 2400 baud, and paces input using CTS
+                if let Some(peer) = &peer {
+                    peer.set_cts_model(CtsModel::Marklin);
+                    peer.set_line_format(LineFormat {
+                        data_bits: 8,
+                        parity: Parity::None,
+                        stop_bits: 2,
+                    });
+                    peer.set_baud(2400, false);
+                }
+
+                let task = iothreads::marklin_to_chans(label.to_string(), timeline, tx, rx);
+                Ok((Some(task), None))
+            }
+            UartCfg::Split { input, output } => spawn_group(
+                label,
+                &[(input, true, false), (output, false, true)],
+                peer,
+                stdio,
+                tx,
+                rx,
+            ),
+            UartCfg::Tee { primary, tees } => {
+                let mut members = vec![(&**primary, true, true)];
+                members.extend(tees.iter().map(|tee| (tee, false, true)));
+                spawn_group(label, &members, peer, stdio, tx, rx)
+            }
+        }
+    }
+}
+
+/// Spawn a group of backends which share a single UART.
+///
+/// Each member is described by a `(cfg, input, output)` tuple: data read from
+/// the backend is only passed to the UART if `input` is set (and is discarded
+/// otherwise), and a copy of the UART's output is only written to the backend
+/// if `output` is set. Only the first member may update the modem lines.
+fn spawn_group(
+    label: &str,
+    members: &[(&UartCfg, bool, bool)],
+    mut peer: Option<PeerHandle>,
+    stdio: &StdioCfg,
+    tx: chan::Sender<u8>,
+    rx: chan::Receiver<u8>,
+) -> Result<(Option<ReaderTask>, Option<WriterTask>), UartCfgError> {
+    let (discard_tx, discard_rx) = chan::unbounded();
+
+    let mut readers: Vec<ReaderTask> = Vec::new();
+    let mut writers: Vec<WriterTask> = Vec::new();
+    let mut idle_writers = Vec::new();
+    let mut sinks = Vec::new();
+    for &(cfg, input, output) in members {
+        let cfg_tx = if input {
+            tx.clone()
+        } else {
+            discard_tx.clone()
+        };
+        let cfg_rx = if output {
+            let (sink_tx, sink_rx) = chan::unbounded();
+            sinks.push(sink_tx);
+            sink_rx
+        } else {
+            chan::never()
+        };
+
+        let (reader, writer) = match cfg.spawn(label, peer.take(), stdio, cfg_tx, cfg_rx) {
+            Ok(tasks) => tasks,
+            Err(e) => {
+                // don't leave the members which were already spawned running
+                for task in readers {
+                    task.cancel();
+                }
+                for task in writers.into_iter().chain(idle_writers) {
+                    task.cancel();
+                }
+                return Err(e);
+            }
+        };
+        readers.extend(reader);
+        match (writer, output) {
+            (Some(writer), true) => writers.push(writer),
+            (Some(writer), false) => idle_writers.push(writer),
+            (None, _) => {}
+        }
+    }
+
+    Ok((
+        Some(iothreads::fan_in(label.to_string(), readers, discard_rx)),
+        Some(iothreads::fan_out(
+            label.to_string(),
+            rx,
+            sinks,
+            writers,
+            idle_writers,
+        )),
+    ))
+}
+
+/// Serve a UART's data over a socket, optionally blocking until the first
+/// client connects.
+fn serve<L: iothreads::Listener>(
+    addr: String,
+    listener: L,
+    wait: bool,
+    peer: Option<PeerHandle>,
+    tx: chan::Sender<u8>,
+    rx: chan::Receiver<u8>,
+) -> std::io::Result<(ReaderTask, WriterTask)> {
+    let initial = if wait {
+        eprintln!("Waiting for a connection on {:?}...", addr);
+        let (stream, client) = listener.accept_client()?;
+        eprintln!("Client connected from {}", client);
+        Some(stream)
+    } else {
+        None
+    };
+
+    Ok(iothreads::server_to_chans(
+        addr,
+        listener,
+        initial,
+        move |connected| {
+            if let Some(peer) = &peer {
+                peer.set_modem_line(ModemLine::Dcd, connected)
+            }
+        },
+        tx,
+        rx,
+    ))
+}
+
+impl Display for UartCfg {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        match self {
+            UartCfg::None => write!(f, "none"),
+            UartCfg::File { out_path, in_path } => {
+                write!(f, "file:{}", out_path)?;
+                if let Some(in_path) = in_path {
+                    write!(f, ",in={}", in_path)?;
+                }
+                Ok(())
+            }
+            UartCfg::Stdio => write!(f, "stdio"),
+            UartCfg::Tcp { host, port } => write!(f, "tcp:{}:{}", host, port),
+            UartCfg::Pty => write!(f, "pty"),
+            UartCfg::Unix { path } => write!(f, "unix:{}", path),
+            UartCfg::Script { path } => write!(f, "script:{}", path),
+            UartCfg::Marklin { timeline } => {
+                write!(f, "marklin")?;
+                if let Some(timeline) = timeline {
+                    write!(f, ":{}", timeline)?;
+                }
+                Ok(())
+            }
+            UartCfg::UnixServer { path, wait } => {
+                write!(f, "unix:{},server", path)?;
+                if *wait {
+                    write!(f, ",wait")?;
+                }
+                Ok(())
+            }
+            UartCfg::TcpServer { host, port, wait } => {
+                write!(f, "tcp:{}:{},server", host, port)?;
+                if *wait {
+                    write!(f, ",wait")?;
+                }
+                Ok(())
+            }
+            UartCfg::Split { input, output } => write!(f, "in:{},out:{}", input, output),
+            UartCfg::Tee { primary, tees } => {
+                write!(f, "{}", primary)?;
+                for tee in tees {
+                    write!(f, ",tee={}", tee)?;
+                }
+                Ok(())
+            }
+        }
     }
 }
 
@@ -93,6 +466,30 @@ impl FromStr for UartCfg {
     type Err = &'static str;
 
     fn from_str(s: &str) -> Result<UartCfg, &'static str> {
+        // in:<cfg>,out:<cfg>
+        if let Some(s) = s.strip_prefix("in:") {
+            let idx = s
+                .find(",out:")
+                .ok_or("expected to find `in:<cfg>,out:<cfg>`")?;
+            return Ok(UartCfg::Split {
+                input: Box::new(s[..idx].parse()?),
+                output: Box::new(s[idx + ",out:".len()..].parse()?),
+            });
+        }
+
+        // <cfg>,tee=<cfg>[,tee=<cfg>...]
+        if let Some(idx) = s.find(",tee=") {
+            let primary = s[..idx].parse()?;
+            let tees = s[idx + ",tee=".len()..]
+                .split(",tee=")
+                .map(str::parse)
+                .collect::<Result<_, _>>()?;
+            return Ok(UartCfg::Tee {
+                primary: Box::new(primary),
+                tees,
+            });
+        }
+
         let mut s = s.split(':');
         let kind = s.next().unwrap();
         Ok(match kind {
@@ -115,23 +512,262 @@ impl FromStr for UartCfg {
                 UartCfg::File { in_path, out_path }
             }
             "stdio" => UartCfg::Stdio,
+            "pty" => UartCfg::Pty,
+            "marklin" => UartCfg::Marklin {
+                timeline: match s.next() {
+                    None => None,
+                    Some("") => return Err("no timeline path specified"),
+                    Some(path) => Some(path.to_string()),
+                },
+            },
+            "script" => match s.next() {
+                None | Some("") => return Err("no script path specified"),
+                Some(path) => UartCfg::Script {
+                    path: path.to_string(),
+                },
+            },
+            "unix" => {
+                let mut s = s.next().ok_or("no socket path specified")?.split(',');
+                let path = match s.next().unwrap() {
+                    "" => return Err("no socket path specified"),
+                    path => path.to_string(),
+                };
+
+                let (mut server, mut wait) = (false, false);
+                for opt in s {
+                    match opt {
+                        "server" => server = true,
+                        "wait" => wait = true,
+                        _ => return Err("invalid unix socket option"),
+                    }
+                }
+
+                match (server, wait) {
+                    (false, false) => UartCfg::Unix { path },
+                    (false, true) => return Err("`wait` can only be used with `server`"),
+                    (true, wait) => UartCfg::UnixServer { path, wait },
+                }
+            }
             "tcp" => {
                 let host = match s.next().ok_or("no host specified")? {
                     "" => "127.0.0.1",
                     other => other,
                 };
-                let port = s
-                    .next()
-                    .ok_or("no port specified")?
-                    .parse()
-                    .map_err(|_| "invalid port")?;
+                let mut s = s.next().ok_or("no port specified")?.split(',');
+                let port = s.next().unwrap().parse().map_err(|_| "invalid port")?;
+
+                let (mut server, mut wait) = (false, false);
+                for opt in s {
+                    match opt {
+                        "server" => server = true,
+                        "wait" => wait = true,
+                        _ => return Err("invalid tcp option"),
+                    }
+                }
 
-                UartCfg::Tcp {
-                    host: host.to_string(),
-                    port,
+                match (server, wait) {
+                    (false, false) => UartCfg::Tcp {
+                        host: host.to_string(),
+                        port,
+                    },
+                    (false, true) => return Err("`wait` can only be used with `server`"),
+                    (true, wait) => UartCfg::TcpServer {
+                        host: host.to_string(),
+                        port,
+                        wait,
+                    },
                 }
             }
             _ => return Err("invalid io type"),
         })
     }
 }
+
+/// Configures the stdio backend, alongside any other hooks backends have into
+/// the system.
+#[derive(Debug, Default, Clone)]
+pub struct StdioCfg {
+    /// Leader key for emulator commands (e.g: `Ctrl-A x` to quit). When set,
+    /// Ctrl-C is passed through to the UART like any other key.
+    pub leader: Option<u8>,
+    /// Where to send commands which must be handled by the system. Required
+    /// by script backends.
+    pub host_cmds: Option<chan::Sender<HostCmd>>,
+    /// Translations applied to data passing to / from the terminal.
+    pub keymap: Keymap,
+}
+
+/// Configures the (electrical) behavior of the device on the other end of a
+/// UART's wire, independent of the backend used to carry its data.
+///
+/// Accepts a comma separated list of `key=val` options:
+///
+/// - `cts=<busy|marklin>`
+/// - `dsr=<0|1>`, `dcd=<0|1>`, `ri=<0|1>`
+#[derive(Debug, Default)]
+pub struct PeerCfg {
+    cts: Option<CtsModel>,
+    modem_lines: Vec<(ModemLine, bool)>,
+    line: Option<LineFormat>,
+    baud: Option<u64>,
+    corrupt_on_mismatch: bool,
+}
+
+impl PeerCfg {
+    /// Apply peer configuration to the specified uart device. Any modem line
+    /// overrides remain in effect when the UART's backend is swapped out.
+    pub fn apply(&self, uart: &mut Uart) {
+        if let Some(cts) = self.cts {
+            uart.set_cts_model(cts);
+        }
+
+        let peer = uart.peer_handle();
+        for &(line, asserted) in &self.modem_lines {
+            peer.override_modem_line(line, asserted);
+        }
+
+        if let Some(format) = self.line {
+            peer.set_line_format(format);
+        }
+
+        if let Some(baud) = self.baud {
+            peer.set_baud(baud, self.corrupt_on_mismatch);
+        }
+    }
+}
+
+impl FromStr for LineFormat {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<LineFormat, &'static str> {
+        let s = s.as_bytes();
+        if s.len() != 3 {
+            return Err("expected line format of the form `8N1`");
+        }
+
+        let data_bits = match s[0] {
+            b'5'..=b'8' => (s[0] - b'0') as u32,
+            _ => return Err("data bits must be between 5 and 8"),
+        };
+        let parity = match s[1].to_ascii_uppercase() {
+            b'N' => Parity::None,
+            b'E' => Parity::Even,
+            b'O' => Parity::Odd,
+            _ => return Err("parity must be one of N, E, or O"),
+        };
+        let stop_bits = match s[2] {
+            b'1' | b'2' => (s[2] - b'0') as u32,
+            _ => return Err("stop bits must be 1 or 2"),
+        };
+
+        Ok(LineFormat {
+            data_bits,
+            parity,
+            stop_bits,
+        })
+    }
+}
+
+impl FromStr for PeerCfg {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<PeerCfg, &'static str> {
+        let mut cfg = PeerCfg::default();
+        for opt in s.split(',').filter(|s| !s.is_empty()) {
+            let mut opt = opt.split('=');
+            let key = opt.next().unwrap();
+            let val = opt.next().ok_or("expected to find `key=val`")?;
+            match key {
+                "cts" => {
+                    cfg.cts = Some(match val {
+                        "busy" => CtsModel::Busy,
+                        "marklin" => CtsModel::Marklin,
+                        _ => return Err("invalid cts model"),
+                    })
+                }
+                "dsr" | "dcd" | "ri" => {
+                    let line = match key {
+                        "dsr" => ModemLine::Dsr,
+                        "dcd" => ModemLine::Dcd,
+                        _ => ModemLine::Ri,
+                    };
+                    let asserted = match val {
+                        "0" => false,
+                        "1" => true,
+                        _ => return Err("expected modem line to be 0 or 1"),
+                    };
+                    cfg.modem_lines.push((line, asserted));
+                }
+                "line" => cfg.line = Some(val.parse()?),
+                "baud" => {
+                    cfg.baud = Some(match val.parse() {
+                        Ok(0) | Err(_) => return Err("invalid baud rate"),
+                        Ok(baud) => baud,
+                    })
+                }
+                "mismatch" => {
+                    cfg.corrupt_on_mismatch = match val {
+                        "warn" => false,
+                        "corrupt" => true,
+                        _ => return Err("expected mismatch to be `warn` or `corrupt`"),
+                    }
+                }
+                _ => return Err("invalid peer option"),
+            }
+        }
+        Ok(cfg)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn uart_cfg_round_trip() {
+        for s in &[
+            "none",
+            "stdio",
+            "pty",
+            "file:/tmp/out",
+            "file:/tmp/out,in=/tmp/in",
+            "tcp:localhost:3018",
+            "tcp:127.0.0.1:3018,server",
+            "tcp:127.0.0.1:3018,server,wait",
+            "unix:/tmp/sock",
+            "unix:/tmp/sock,server,wait",
+            "script:/tmp/script",
+            "marklin",
+            "marklin:/tmp/timeline",
+            "in:tcp:127.0.0.1:3018,out:file:/tmp/out",
+            "stdio,tee=file:/tmp/out,tee=unix:/tmp/sock",
+        ] {
+            assert_eq!(&s.parse::<UartCfg>().unwrap().to_string(), s);
+        }
+
+        // the host defaults to localhost
+        assert_eq!(
+            "tcp::3018".parse::<UartCfg>().unwrap().to_string(),
+            "tcp:127.0.0.1:3018"
+        );
+    }
+
+    #[test]
+    fn uart_cfg_errors() {
+        for bad in &[
+            "serial",
+            "file",
+            "tcp:localhost",
+            "tcp:localhost:port",
+            "tcp:localhost:3018,wait",
+            "unix:",
+            "unix:/tmp/sock,client",
+            "script:",
+            "marklin:",
+            "in:stdio",
+            "stdio,tee=serial",
+        ] {
+            assert!(bad.parse::<UartCfg>().is_err(), "{:?}", bad);
+        }
+    }
+}
@@ -1,15 +1,17 @@
 use std::collections::VecDeque;
 use std::sync::{Arc, Mutex};
 use std::thread::{self, JoinHandle};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use crossbeam_channel::{self as chan, select};
 
 use crate::devices::{vic::Interrupt, Device, Probe};
 use crate::memory::{MemException::*, MemResult, Memory};
 
+use super::{TranscriptEvent, TranscriptHandle};
+
 /// Aggregate type to configure which Interrupts should be generated by the UART
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub struct UartInterrupts {
     pub rx: Interrupt,
     pub tx: Interrupt,
@@ -43,10 +45,107 @@ pub mod interrupts {
     };
 }
 
-/// Derived from section 14 of the EP93xx User's Guide and the provided value
-/// for bauddiv from CS452.
-// TODO: A better source for UARTCLK_HZ would be appreciated.
-const UARTCLK_HZ: u64 = 7_372_800;
+/// UARTCLK frequency used until the Syscon's clock configuration is applied
+/// (i.e: XTALI / 2, as configured on the TS-7200)
+const DEFAULT_UARTCLK_HZ: u64 = 7_372_800;
+
+/// Baud rates a guest is likely trying to hit
+const STANDARD_BAUD_RATES: [u64; 12] = [
+    300, 1200, 2400, 4800, 9600, 19200, 38400, 57600, 115_200, 230_400, 460_800, 921_600,
+];
+
+/// Maximum baud rate error (in percent) before the guest and peer can no
+/// longer reliably talk to one another.
+const BAUD_TOLERANCE_PCT: u64 = 3;
+
+/// Rough approximation of how long the Märklin 6051 holds CTS low while it
+/// processes a received byte.
+const MARKLIN_CTS_HOLD: Duration = Duration::from_millis(5);
+
+/// Ctrl register loopback enable bit
+const CTRL_LBEN: u32 = 0x80;
+
+// DMACtrl register bits
+const DMACTRL_RXDMAE: u32 = 0x1;
+const DMACTRL_TXDMAE: u32 = 0x2;
+const DMACTRL_DMAONERR: u32 = 0x4;
+
+/// LinCtrlHigh send break bit
+const LINCTRL_BRK: u32 = 0x01;
+
+// Receive error bits, as reported in bits 8-11 of the Data register (and bits
+// 0-3 of the RXSts register)
+const RX_FE: u16 = 0x100;
+const RX_PE: u16 = 0x200;
+const RX_BE: u16 = 0x400;
+const RX_OE: u16 = 0x800;
+
+/// UART parity settings
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Parity {
+    None,
+    Even,
+    Odd,
+}
+
+/// Character framing used on a UART line (e.g: 8N1)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LineFormat {
+    pub data_bits: u32,
+    pub parity: Parity,
+    pub stop_bits: u32,
+}
+
+impl LineFormat {
+    /// Total number of bits on the wire per character (including start bit)
+    fn frame_bits(&self) -> u32 {
+        1 + self.data_bits + (self.parity != Parity::None) as u32 + self.stop_bits
+    }
+}
+
+impl std::fmt::Display for LineFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let parity = match self.parity {
+            Parity::None => 'N',
+            Parity::Even => 'E',
+            Parity::Odd => 'O',
+        };
+        write!(f, "{}{}{}", self.data_bits, parity, self.stop_bits)
+    }
+}
+
+/// Determines how the CTS line of a UART is driven.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CtsModel {
+    /// CTS is deasserted whenever the UART is transmitting. Not representative
+    /// of any real hardware, but forgiving.
+    Busy,
+    /// Models the Märklin 6051: CTS is deasserted after each received byte,
+    /// and is only reasserted once the byte has been processed. Any bytes sent
+    /// while CTS is deasserted are dropped.
+    Marklin,
+}
+
+/// Modem status lines, as reported by UART1's MdmSts register.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ModemLine {
+    Cts,
+    Dsr,
+    Ri,
+    Dcd,
+}
+
+impl ModemLine {
+    /// Bit corresponding to the line's current state in MdmSts
+    fn mask(self) -> u8 {
+        match self {
+            ModemLine::Cts => 0x10,
+            ModemLine::Dsr => 0x20,
+            ModemLine::Ri => 0x40,
+            ModemLine::Dcd => 0x80,
+        }
+    }
+}
 
 /// UART internal register state.
 ///
@@ -62,23 +161,47 @@ struct State {
     linctrl_latch: [u32; 3],
     linctrl: [u32; 3],
     ctrl: u32,
+    dma_ctrl: u32,
+    ilpr: u32,
+    // HDLCCtl, HDLCAddMtchVal, HDLCAddMask
+    hdlc: [u32; 3],
 
-    // FIXME: Need to separate out bit time for the timeout interrupt
+    uartclk_hz: u64,
     bittime: Duration,
+    // how long the rx line must be idle before a receive timeout is flagged
+    rx_timeout: Duration,
+    format: LineFormat,
     word_len: u32,
     fifo_size: usize,
     overrun: bool,
+    // receive status of the last character read from the Data register
+    rx_sts: u16,
     busy: bool,
 
-    timeout: bool,
-    cts_change: bool,
+    baud: u64,
+    // line settings used by the peer, if declared
+    peer_format: Option<LineFormat>,
+    peer_baud: Option<u64>,
+    // whether bytes sent at a mismatched baud rate should be garbled
+    corrupt_on_mismatch: bool,
 
-    rx_buf: VecDeque<u8>,
+    timeout: bool,
+    // modem status lines + delta bits, in the MdmSts register format
+    modem_sts: u8,
+    // modem status lines pinned via the peer configuration
+    modem_overrides: u8,
+    modem_ctl: u32,
+    cts_model: CtsModel,
+
+    // received characters, alongside their receive error bits
+    rx_buf: VecDeque<u16>,
     tx_buf_size: usize,
 
     rx_int_asserted: bool,
     tx_int_asserted: bool,
     combo_int_asserted: bool,
+
+    transcript: Option<TranscriptHandle>,
 }
 
 impl State {
@@ -93,16 +216,34 @@ impl State {
             linctrl_latch: [0, 0, 0],
             linctrl: [0, 0, 0],
             ctrl: 0,
+            dma_ctrl: 0,
+            ilpr: 0,
+            hdlc: [0; 3],
 
             // set to proper defaults once update_linctrl is called below
+            uartclk_hz: DEFAULT_UARTCLK_HZ,
             bittime: Duration::default(),
+            rx_timeout: Duration::default(),
+            format: LineFormat {
+                data_bits: 8,
+                parity: Parity::None,
+                stop_bits: 1,
+            },
             word_len: 0,
             fifo_size: 0,
 
             overrun: false,
+            rx_sts: 0,
             busy: false,
+            baud: 0,
+            peer_format: None,
+            peer_baud: None,
+            corrupt_on_mismatch: false,
             timeout: false,
-            cts_change: false,
+            modem_sts: ModemLine::Cts.mask(),
+            modem_overrides: 0,
+            modem_ctl: 0,
+            cts_model: CtsModel::Busy,
 
             rx_buf: VecDeque::new(),
             tx_buf_size: 0,
@@ -110,6 +251,8 @@ impl State {
             rx_int_asserted: false,
             tx_int_asserted: false,
             combo_int_asserted: false,
+
+            transcript: None,
         };
         s.update_linctrl();
         s
@@ -131,16 +274,290 @@ impl State {
         s
     }
 
+    /// Reset the registers to their post-boot state.
+    ///
+    /// Anything determined by the outside world (i.e: the peer's settings, the
+    /// modem status lines, and any bytes still being shifted out) is preserved.
+    fn reset_hle(&mut self) {
+        let mut s = State::new_hle(self.label, self.interrupts);
+        s.hack_inf_uart_rx = self.hack_inf_uart_rx;
+        s.hack_nodelay_uart_tx = self.hack_nodelay_uart_tx;
+        s.uartclk_hz = self.uartclk_hz;
+        s.busy = self.busy;
+        s.tx_buf_size = self.tx_buf_size;
+        s.peer_format = self.peer_format;
+        s.peer_baud = self.peer_baud;
+        s.corrupt_on_mismatch = self.corrupt_on_mismatch;
+        // delta bits are cleared
+        s.modem_sts = self.modem_sts & 0xf0;
+        s.modem_overrides = self.modem_overrides;
+        s.cts_model = self.cts_model;
+        s.transcript = self.transcript.take();
+        s.update_linctrl();
+        *self = s;
+    }
+
     fn update_linctrl(&mut self) {
         let high = self.linctrl[0];
-        let bauddiv = ((self.linctrl[1] & 0xff) as u64) << 32 | (self.linctrl[2] as u64);
-        let baud = UARTCLK_HZ / 16 / (bauddiv + 1);
-        self.bittime = Duration::from_nanos(1_000_000_000 / baud);
-        self.word_len = 1 // start bit
-            + 8 // word length TODO: Allow for other word lengths than 8
-            + (if high & 0x8 != 0 { 2 } else { 1 }) // stop bits
-            + (if high & 0x2 != 0 { 1 } else { 0 }); // parity bit
-        self.fifo_size = if (high & 0x10) != 0 { 16 } else { 1 }
+        let bauddiv = self.bauddiv();
+        self.baud = self.uartclk_hz / 16 / (bauddiv + 1);
+        // computed straight from the divisor to avoid accumulating rounding
+        // errors from the (truncated) baud rate
+        let bit_ns = 16 * (bauddiv + 1) * 1_000_000_000 / self.uartclk_hz;
+        self.bittime = Duration::from_nanos(bit_ns);
+        // The receive timeout fires once the rx line has been idle for 32 bit
+        // periods (see section 14.2.5 of the EP93xx User's Guide).
+        self.rx_timeout = Duration::from_nanos(32 * bit_ns);
+        self.format = LineFormat {
+            data_bits: 5 + ((high >> 5) & 0x3),
+            parity: match (high & 0x2 != 0, high & 0x4 != 0) {
+                (false, _) => Parity::None,
+                (true, true) => Parity::Even,
+                (true, false) => Parity::Odd,
+            },
+            stop_bits: if high & 0x8 != 0 { 2 } else { 1 },
+        };
+        self.word_len = self.format.frame_bits();
+        self.fifo_size = if (high & 0x10) != 0 { 16 } else { 1 };
+
+        if high & LINCTRL_BRK != 0 {
+            debug!("UART {} sending break", self.label);
+        }
+        self.check_peer_format();
+    }
+
+    fn bauddiv(&self) -> u64 {
+        ((self.linctrl[1] & 0xff) as u64) << 8 | (self.linctrl[2] as u64)
+    }
+
+    /// Log the baud rate the guest's divisor actually achieves, and how far
+    /// off it is from the nearest standard baud rate.
+    fn report_baud(&self) {
+        let bauddiv = self.bauddiv();
+        let achieved = self.uartclk_hz as f64 / (16 * (bauddiv + 1)) as f64;
+        let nearest = *STANDARD_BAUD_RATES
+            .iter()
+            .min_by_key(|&&std| (std as f64 - achieved).abs() as u64)
+            .unwrap();
+        let error = (achieved - nearest as f64) / nearest as f64 * 100.0;
+
+        let msg = format!(
+            "UART {} divisor {} @ {} Hz UARTCLK gives {:.1} baud ({:+.2}% from {} {})",
+            self.label, bauddiv, self.uartclk_hz, achieved, error, nearest, self.format
+        );
+        if error.abs() > BAUD_TOLERANCE_PCT as f64 {
+            warn!("{}", msg);
+        } else {
+            info!("{}", msg);
+        }
+    }
+
+    /// Warn if the guest's line settings don't match the peer's.
+    fn check_peer_format(&self) {
+        match self.peer_format {
+            Some(peer) if peer != self.format => warn!(
+                "UART {} configured for {}, but the peer is using {}. Expect garbled data!",
+                self.label, self.format, peer
+            ),
+            _ => {}
+        }
+
+        if self.baud_mismatch() {
+            warn!(
+                "UART {} configured for {} baud, but the peer is using {} baud. Expect garbled data!",
+                self.label,
+                self.baud,
+                self.peer_baud.unwrap()
+            );
+        }
+    }
+
+    /// Whether the guest's baud rate is too far off from the peer's for
+    /// characters to be received correctly.
+    fn baud_mismatch(&self) -> bool {
+        match self.peer_baud {
+            Some(peer) => {
+                let diff = if peer > self.baud {
+                    peer - self.baud
+                } else {
+                    self.baud - peer
+                };
+                diff * 100 > peer * BAUD_TOLERANCE_PCT
+            }
+            None => false,
+        }
+    }
+
+    /// Determine what the guest actually receives when the peer sends `b`,
+    /// returning the (possibly garbled) character alongside any error bits.
+    fn rx_char(&self, b: u8) -> (u8, u16) {
+        if self.corrupt_on_mismatch && self.baud_mismatch() {
+            self.resample(b)
+        } else {
+            (b, self.rx_errors())
+        }
+    }
+
+    /// Simulate the guest sampling a character sent by the peer at a
+    /// different baud rate.
+    fn resample(&self, b: u8) -> (u8, u16) {
+        // UARTCLK is too slow for the divisor, so the guest never even gets
+        // to sample the start bit
+        if self.baud == 0 {
+            return (0, RX_FE);
+        }
+
+        let peer = self.peer_format.unwrap_or(self.format);
+        let peer_baud = self.peer_baud.unwrap_or(self.baud);
+
+        // The peer's frame, one entry per bit period (LSB first), starting
+        // with the start bit. The line idles high once the frame is over.
+        let mut frame = vec![false];
+        frame.extend((0..peer.data_bits).map(|i| b & (1 << i) != 0));
+        if peer.parity != Parity::None {
+            let ones = (b & ((1u16 << peer.data_bits) - 1) as u8).count_ones();
+            frame.push((ones % 2 == 1) ^ (peer.parity == Parity::Odd));
+        }
+        frame.extend((0..peer.stop_bits).map(|_| true));
+
+        // The guest syncs on the falling edge of the start bit, and samples
+        // the middle of each of its own bit periods.
+        let sample = |bit: u32| -> bool {
+            let t = (2 * bit as u64 + 1) * peer_baud / (2 * self.baud);
+            frame.get(t as usize).copied().unwrap_or(true)
+        };
+
+        let mut data = 0;
+        for i in 0..self.format.data_bits {
+            data |= (sample(1 + i) as u8) << i;
+        }
+        let mut next = 1 + self.format.data_bits;
+
+        let mut errors = 0;
+        if self.format.parity != Parity::None {
+            let parity = (data.count_ones() % 2 == 1) ^ (self.format.parity == Parity::Odd);
+            if sample(next) != parity {
+                errors |= RX_PE;
+            }
+            next += 1;
+        }
+        if !sample(next) {
+            errors |= RX_FE;
+        }
+
+        (data, errors)
+    }
+
+    /// Compute the error bits of a character received from the peer.
+    fn rx_errors(&self) -> u16 {
+        let peer = match self.peer_format {
+            Some(peer) => peer,
+            None => return 0,
+        };
+
+        let mut errors = 0;
+        // The guest will sample the wrong bit when looking for the stop bit
+        if peer.data_bits != self.format.data_bits {
+            errors |= RX_FE;
+        }
+        if peer.parity != self.format.parity && self.format.parity != Parity::None {
+            errors |= RX_PE;
+        }
+        errors
+    }
+
+    fn record(&self, event: TranscriptEvent) {
+        if let Some(transcript) = &self.transcript {
+            transcript.record(event);
+        }
+    }
+
+    fn modem_line(&self, line: ModemLine) -> bool {
+        self.modem_sts & line.mask() != 0
+    }
+
+    /// Set the level of one of the modem status lines without flagging the
+    /// change to the guest (i.e: leaving the delta bits untouched).
+    fn init_modem_line(&mut self, line: ModemLine, asserted: bool) {
+        if asserted {
+            self.modem_sts |= line.mask();
+        } else {
+            self.modem_sts &= !line.mask();
+        }
+    }
+
+    /// Drive one of the modem status lines, setting the corresponding delta bit
+    /// (and raising the modem status interrupt) if it changed.
+    fn set_modem_line(
+        &mut self,
+        line: ModemLine,
+        asserted: bool,
+        interrupt_bus: &chan::Sender<(Interrupt, bool)>,
+    ) {
+        if self.modem_line(line) == asserted {
+            return;
+        }
+
+        trace!("UART {} setting {:?} to {}", self.label, line, asserted);
+        if line == ModemLine::Cts {
+            self.record(TranscriptEvent::Cts(asserted));
+        }
+        if asserted {
+            self.modem_sts |= line.mask();
+        } else {
+            self.modem_sts &= !line.mask();
+        }
+
+        // delta bits live 4 bits below the line bits. RI only flags trailing edges.
+        if line != ModemLine::Ri || !asserted {
+            self.modem_sts |= line.mask() >> 4;
+        }
+        self.update_interrupts(interrupt_bus);
+    }
+
+    /// Push a received byte (with any associated error bits) into the RX FIFO.
+    /// Bits beyond the configured word length are lost.
+    fn receive_byte(
+        &mut self,
+        b: u8,
+        errors: u16,
+        interrupt_bus: &chan::Sender<(Interrupt, bool)>,
+    ) {
+        let data = b as u16 & ((1 << self.format.data_bits) - 1);
+        if self.rx_buf.len() < self.fifo_size || self.hack_inf_uart_rx {
+            self.record(TranscriptEvent::Rx {
+                byte: data as u8,
+                errors: (errors >> 8) as u8,
+            });
+            self.rx_buf.push_back(data | errors);
+            self.update_interrupts(interrupt_bus);
+        } else {
+            self.record(TranscriptEvent::Rx {
+                byte: data as u8,
+                errors: ((errors | RX_OE) >> 8) as u8,
+            });
+            warn!(
+                "UART {} dropping received byte due to full FIFO",
+                self.label
+            );
+            self.overrun = true;
+        }
+    }
+
+    /// Called once a byte has been shifted out of the TX FIFO
+    fn tx_complete(&mut self, interrupt_bus: &chan::Sender<(Interrupt, bool)>) {
+        self.tx_buf_size -= 1;
+        if self.tx_buf_size == 0 {
+            self.busy = false;
+            if self.cts_model == CtsModel::Busy {
+                self.set_cts(true, interrupt_bus);
+            }
+        }
+        self.update_interrupts(interrupt_bus);
+    }
+
+    fn set_cts(&mut self, cts: bool, interrupt_bus: &chan::Sender<(Interrupt, bool)>) {
+        self.set_modem_line(ModemLine::Cts, cts, interrupt_bus)
     }
 
     /// Returns the interrupt status in the format of the UARTxIntIDIntClr
@@ -156,7 +573,7 @@ impl State {
         if self.rx_buf.len() * 2 >= self.fifo_size {
             result |= 2;
         }
-        if self.cts_change {
+        if self.modem_sts & 0x0f != 0 {
             result |= 1;
         }
 
@@ -192,11 +609,26 @@ impl State {
 
 struct Exit;
 
+/// Something arriving on a UART's rx line, other than a regular byte from the
+/// backend
+#[derive(Debug, Clone, Copy)]
+enum RxEvent {
+    /// A character from the peer, received with the specified error bits
+    /// forced on
+    Char { byte: u8, errors: u16 },
+    /// The peer holding the line low for longer than a character (i.e: a
+    /// break)
+    Break,
+    /// A character fed back from the UART's own tx line in loopback mode
+    Loopback { byte: u8, errors: u16 },
+}
+
 /// Structured return type for the various channels created as part of spawning
 /// a UART input buffer thread
 struct InputBufferThreadChans {
     pub exit: chan::Sender<Exit>,
     pub uart_input: chan::Sender<u8>,
+    pub rx_event: chan::Sender<RxEvent>,
 }
 
 fn spawn_input_buffer_thread(
@@ -205,47 +637,70 @@ fn spawn_input_buffer_thread(
     interrupt_bus: chan::Sender<(Interrupt, bool)>,
 ) -> (JoinHandle<()>, InputBufferThreadChans) {
     let (uart_tx, uart_rx) = chan::unbounded();
+    let (event_tx, event_rx) = chan::unbounded();
     let (exit_tx, exit_rx) = chan::bounded(1);
     let thread = move || loop {
-        let (can_timeout, bittime, word_len) = {
+        // The timeout counter runs whenever there's data sitting in the rx
+        // FIFO, regardless of RTIEN (which only masks the resulting interrupt)
+        let (can_timeout, rx_timeout, bittime, word_len) = {
             let state = state.lock().unwrap();
             (
                 !state.rx_buf.is_empty() && !state.timeout,
+                state.rx_timeout,
                 state.bittime,
                 state.word_len,
             )
         };
-        let b = if can_timeout {
+        let ev = if can_timeout {
             select! {
                 recv(uart_rx) -> b => match b {
-                    Ok(b) => Some(b),
+                    Ok(b) => Some(RxEvent::Char { byte: b, errors: 0 }),
                     Err(chan::RecvError) => panic!("uart_rx closed unexpectedly"),
                 },
+                recv(event_rx) -> ev => match ev {
+                    Ok(ev) => Some(ev),
+                    Err(chan::RecvError) => panic!("event_rx closed unexpectedly"),
+                },
                 recv(exit_rx) -> _ => break,
-                default(bittime * 32) => None,
+                default(rx_timeout) => None,
             }
         } else {
             select! {
                 recv(uart_rx) -> b => match b {
-                    Ok(b) => Some(b),
+                    Ok(b) => Some(RxEvent::Char { byte: b, errors: 0 }),
                     Err(chan::RecvError) => panic!("uart_rx closed unexpectedly"),
                 },
+                recv(event_rx) -> ev => match ev {
+                    Ok(ev) => Some(ev),
+                    Err(chan::RecvError) => panic!("event_rx closed unexpectedly"),
+                },
                 recv(exit_rx) -> _ => break,
             }
         };
 
-        match b {
-            Some(b) => {
-                thread::sleep(bittime * word_len);
+        match ev {
+            Some(ev) => {
+                let looped_back = matches!(ev, RxEvent::Loopback { .. });
+                if !looped_back {
+                    // looped back characters were already delayed as they
+                    // were shifted out
+                    thread::sleep(bittime * word_len);
+                }
 
                 let mut state = state.lock().unwrap();
-                if state.rx_buf.len() < state.fifo_size || state.hack_inf_uart_rx {
-                    state.rx_buf.push_back(b);
-                    state.update_interrupts(&interrupt_bus);
-                } else {
-                    warn!("UART {} dropping received byte due to full FIFO", label);
-                    state.overrun = true;
+                if !looped_back && state.ctrl & CTRL_LBEN != 0 {
+                    // the UART's rx line is disconnected from the peer in loopback mode
+                    continue;
                 }
+                let (b, errors) = match ev {
+                    RxEvent::Char { byte, errors } => {
+                        let (b, line_errors) = state.rx_char(byte);
+                        (b, line_errors | errors)
+                    }
+                    RxEvent::Break => (0, RX_BE | RX_FE),
+                    RxEvent::Loopback { byte, errors } => (byte, errors),
+                };
+                state.receive_byte(b, errors, &interrupt_bus);
             }
             None => {
                 let mut state = state.lock().unwrap();
@@ -267,6 +722,58 @@ fn spawn_input_buffer_thread(
         InputBufferThreadChans {
             exit: exit_tx,
             uart_input: uart_tx,
+            rx_event: event_tx,
+        },
+    )
+}
+
+/// Structured return type for the various channels created as part of spawning
+/// a UART CTS thread
+struct CtsThreadChans {
+    pub exit: chan::Sender<Exit>,
+    pub reassert: chan::Sender<Instant>,
+}
+
+/// Spawn a thread which reasserts CTS at the deadlines sent via `reassert`.
+fn spawn_cts_thread(
+    label: &'static str,
+    state: Arc<Mutex<State>>,
+    interrupt_bus: chan::Sender<(Interrupt, bool)>,
+) -> (JoinHandle<()>, CtsThreadChans) {
+    let (tx, rx) = chan::unbounded::<Instant>();
+    let (exit_tx, exit_rx) = chan::bounded(1);
+    let thread = move || {
+        let mut next: Option<Instant> = None;
+        loop {
+            let timeout = match next {
+                Some(next) => chan::after(next.saturating_duration_since(Instant::now())),
+                None => chan::never(),
+            };
+
+            select! {
+                recv(rx) -> deadline => match deadline {
+                    Ok(deadline) => next = Some(deadline),
+                    Err(chan::RecvError) => return,
+                },
+                recv(exit_rx) -> _ => return,
+                recv(timeout) -> _ => {
+                    next = None;
+                    state.lock().unwrap().set_cts(true, &interrupt_bus);
+                }
+            }
+        }
+    };
+
+    let handle = thread::Builder::new()
+        .name(format!("{} | UART CTS", label))
+        .spawn(thread)
+        .unwrap();
+
+    (
+        handle,
+        CtsThreadChans {
+            exit: exit_tx,
+            reassert: tx,
         },
     )
 }
@@ -279,10 +786,15 @@ struct OutputBufferThreadChans {
     pub device_output: chan::Sender<u8>,
 }
 
+/// Bytes transmitted in loopback mode are sent to the input buffer thread via
+/// `loopback`, so that they're subject to the same RX timeout as regular input.
+/// In Marklin CTS mode, CTS reassertion deadlines are sent via `cts_reassert`.
 fn spawn_output_buffer_thread(
     label: &'static str,
     state: Arc<Mutex<State>>,
     interrupt_bus: chan::Sender<(Interrupt, bool)>,
+    loopback: chan::Sender<RxEvent>,
+    cts_reassert: chan::Sender<Instant>,
 ) -> (JoinHandle<()>, OutputBufferThreadChans) {
     let (uart_tx, uart_rx) = chan::unbounded();
     let (device_tx, device_rx) = chan::unbounded();
@@ -298,38 +810,90 @@ fn spawn_output_buffer_thread(
             };
 
             // Sleep for the appropriate time
-            let (bittime, word_len, hack_nodelay_uart_tx) = {
+            let (b, bittime, word_len, hack_nodelay_uart_tx, cts_at_start) = {
                 let mut state = state.lock().unwrap();
                 if !state.busy {
                     state.busy = true;
-                    state.cts_change = true;
-                    state.update_interrupts(&interrupt_bus);
+                    if state.cts_model == CtsModel::Busy {
+                        state.set_cts(false, &interrupt_bus);
+                    }
                 }
 
-                (state.bittime, state.word_len, state.hack_nodelay_uart_tx)
+                (
+                    // bits beyond the configured word length never hit the wire
+                    b & ((1u16 << state.format.data_bits) - 1) as u8,
+                    state.bittime,
+                    state.word_len,
+                    state.hack_nodelay_uart_tx,
+                    state.modem_line(ModemLine::Cts),
+                )
             };
 
             if !hack_nodelay_uart_tx {
                 thread::sleep(bittime * word_len);
             }
 
-            match uart_tx.send(b) {
-                Ok(()) => (),
-                Err(chan::SendError(_)) => {
-                    // Receiving end closed
-                    return;
+            // whether the byte makes it to the peer
+            let forward = {
+                let mut state = state.lock().unwrap();
+                if state.ctrl & CTRL_LBEN != 0 {
+                    // In loopback mode, the tx line is fed straight back into the
+                    // UART's own rx FIFO, and the peer never sees the byte.
+                    let ev = if state.linctrl[0] & LINCTRL_BRK != 0 {
+                        // a break reads as an all-zero character with a framing error
+                        RxEvent::Loopback {
+                            byte: 0,
+                            errors: RX_BE | RX_FE,
+                        }
+                    } else {
+                        RxEvent::Loopback { byte: b, errors: 0 }
+                    };
+                    // the input buffer thread only exits when the UART is dropped
+                    let _ = loopback.send(ev);
+                    false
+                } else {
+                    match state.cts_model {
+                        CtsModel::Busy => true,
+                        CtsModel::Marklin => {
+                            state.set_cts(false, &interrupt_bus);
+                            // the CTS thread is only stopped after this thread exits
+                            cts_reassert
+                                .send(Instant::now() + MARKLIN_CTS_HOLD)
+                                .unwrap();
+                            if !cts_at_start {
+                                warn!(
+                                    "UART {} peer dropped byte {:#04x?} (sent while CTS was deasserted)",
+                                    label, b
+                                );
+                            }
+                            cts_at_start
+                        }
+                    }
                 }
-            }
+            };
+
             {
-                let mut state = state.lock().unwrap();
+                let state = state.lock().unwrap();
+                // bytes looped back are recorded as they're received instead
+                if state.ctrl & CTRL_LBEN == 0 {
+                    state.record(TranscriptEvent::Tx {
+                        byte: b,
+                        dropped: !forward,
+                    });
+                }
+            }
 
-                state.tx_buf_size -= 1;
-                if state.tx_buf_size == 0 {
-                    state.busy = false;
-                    state.cts_change = true;
+            if forward {
+                match uart_tx.send(b) {
+                    Ok(()) => (),
+                    Err(chan::SendError(_)) => {
+                        // Receiving end closed
+                        return;
+                    }
                 }
-                state.update_interrupts(&interrupt_bus);
             }
+
+            state.lock().unwrap().tx_complete(&interrupt_bus);
         }
         for b in device_rx.try_iter() {
             uart_tx.send(b).expect("io receiver closed unexpectedly")
@@ -355,25 +919,52 @@ fn spawn_output_buffer_thread(
 #[derive(Debug)]
 pub struct ReaderTask {
     handle: JoinHandle<()>,
+    exit: chan::Sender<()>,
 }
 
 impl ReaderTask {
-    /// Create a new ReaderTask
-    pub fn new(handle: JoinHandle<()>) -> ReaderTask {
-        ReaderTask { handle }
+    /// Create a new ReaderTask. The task's thread is expected to exit shortly
+    /// after `exit` is signalled or dropped.
+    pub fn new(handle: JoinHandle<()>, exit: chan::Sender<()>) -> ReaderTask {
+        ReaderTask { handle, exit }
+    }
+
+    /// Signal the task to exit, and wait for it to do so.
+    pub fn cancel(self) {
+        // the task may have already exited on its own (e.g: on EOF)
+        let _ = self.exit.send(());
+        if self.handle.join().is_err() {
+            error!("UART reader task panicked");
+        }
     }
 }
 
-/// User-provided task for providing input into a UART
+/// User-provided task for consuming output from a UART
 #[derive(Debug)]
 pub struct WriterTask {
     handle: JoinHandle<()>,
+    exit: chan::Sender<()>,
 }
 
 impl WriterTask {
-    /// Create a new WriterTask
-    pub fn new(handle: JoinHandle<()>) -> WriterTask {
-        WriterTask { handle }
+    /// Create a new WriterTask. The task's thread is expected to exit shortly
+    /// after `exit` is signalled or dropped.
+    pub fn new(handle: JoinHandle<()>, exit: chan::Sender<()>) -> WriterTask {
+        WriterTask { handle, exit }
+    }
+
+    /// Signal the task to exit, and wait for it to do so. Any data the task
+    /// hasn't consumed yet is left for the next WriterTask.
+    pub fn cancel(self) {
+        let _ = self.exit.send(());
+        self.join();
+    }
+
+    /// Wait for the task to exit on its own (i.e: once its channel hangs up).
+    pub fn join(self) {
+        if self.handle.join().is_err() {
+            error!("UART writer task panicked");
+        }
     }
 }
 
@@ -387,11 +978,14 @@ impl WriterTask {
 struct UartWorker {
     input_buffer_thread_exit: chan::Sender<Exit>,
     output_buffer_thread_exit: chan::Sender<Exit>,
+    cts_thread_exit: chan::Sender<Exit>,
     // must be optional, as `.join()` can only be called on an owned JoinHandle
     input_buffer_thread: Option<JoinHandle<()>>,
     output_buffer_thread: Option<JoinHandle<()>>,
+    cts_thread: Option<JoinHandle<()>>,
 
     uart_input_chan: chan::Sender<u8>,
+    rx_event_chan: chan::Sender<RxEvent>,
     uart_output_chan: chan::Receiver<u8>,
     device_output_chan: chan::Sender<u8>,
 
@@ -408,20 +1002,27 @@ impl Drop for UartWorker {
             .send(Exit)
             .expect("uart worker writer thread was unexpectedly terminated");
 
-        self.input_buffer_thread.take().unwrap().join().unwrap();
-        self.output_buffer_thread.take().unwrap().join().unwrap();
+        // panicking while dropping would abort, so just report any panics
+        if self.input_buffer_thread.take().unwrap().join().is_err() {
+            error!("UART input buffer thread panicked");
+        }
+        if self.output_buffer_thread.take().unwrap().join().is_err() {
+            error!("UART output buffer thread panicked");
+        }
 
-        // HACK: don't actually join on the user_reader_thread
-        // reader threads are typically blocked on IO, and don't have an easy way to
-        // check if the other end of their send channel has closed.
-        // TODO: provide a mechanism to cleanly close ReaderTask tasks
+        // the output buffer thread schedules CTS reassertions, so the CTS
+        // thread must only be stopped once it has exited
+        let _ = self.cts_thread_exit.send(Exit);
+        if self.cts_thread.take().unwrap().join().is_err() {
+            error!("UART CTS thread panicked");
+        }
 
-        // if let Some(user_reader_task) = self.user_reader_task.take() {
-        //     user_reader_task.0.join().unwrap();
-        // }
+        if let Some(user_reader_task) = self.user_reader_task.take() {
+            user_reader_task.cancel();
+        }
 
         if let Some(user_writer_task) = self.user_writer_task.take() {
-            user_writer_task.handle.join().unwrap();
+            user_writer_task.join();
         };
     }
 }
@@ -434,15 +1035,24 @@ impl UartWorker {
     ) -> UartWorker {
         let (input_buffer_thread, input_chans) =
             spawn_input_buffer_thread(label, state.clone(), interrupt_bus.clone());
-        let (output_buffer_thread, output_chans) =
-            spawn_output_buffer_thread(label, state, interrupt_bus);
+        let (cts_thread, cts_chans) = spawn_cts_thread(label, state.clone(), interrupt_bus.clone());
+        let (output_buffer_thread, output_chans) = spawn_output_buffer_thread(
+            label,
+            state,
+            interrupt_bus,
+            input_chans.rx_event.clone(),
+            cts_chans.reassert,
+        );
 
         UartWorker {
             input_buffer_thread_exit: input_chans.exit,
             output_buffer_thread_exit: output_chans.exit,
+            cts_thread_exit: cts_chans.exit,
             input_buffer_thread: Some(input_buffer_thread),
             output_buffer_thread: Some(output_buffer_thread),
+            cts_thread: Some(cts_thread),
             uart_input_chan: input_chans.uart_input,
+            rx_event_chan: input_chans.rx_event,
             uart_output_chan: output_chans.uart_output,
             device_output_chan: output_chans.device_output,
             user_reader_task: None,
@@ -451,14 +1061,116 @@ impl UartWorker {
     }
 }
 
+/// Handle used by whatever is on the other end of a UART's wire to drive the
+/// UART's modem status lines, and to inject line errors.
+#[derive(Debug, Clone)]
+pub struct PeerHandle {
+    state: Arc<Mutex<State>>,
+    interrupt_bus: chan::Sender<(Interrupt, bool)>,
+    input: chan::Sender<RxEvent>,
+    // whether the UART has DSR / DCD / RI lines (i.e: it's UART1)
+    has_modem_lines: bool,
+}
+
+impl PeerHandle {
+    /// Declare the framing used by the peer. Any mismatch with the guest's
+    /// configuration will result in receive errors.
+    pub fn set_line_format(&self, format: LineFormat) {
+        let mut state = self.state.lock().unwrap();
+        state.peer_format = Some(format);
+        state.check_peer_format();
+    }
+
+    /// Declare the baud rate used by the peer. If `corrupt` is set, bytes
+    /// received while the guest's baud rate doesn't match are garbled.
+    pub fn set_baud(&self, baud: u64, corrupt: bool) {
+        let mut state = self.state.lock().unwrap();
+        state.peer_baud = Some(baud);
+        state.corrupt_on_mismatch = corrupt;
+        state.check_peer_format();
+    }
+
+    /// Whether the peer is in control of the specified modem status line.
+    fn drives(&self, state: &State, line: ModemLine) -> bool {
+        line == ModemLine::Cts || (self.has_modem_lines && state.modem_overrides & line.mask() == 0)
+    }
+
+    /// Assert / deassert one of the UART's modem status lines. Lines which
+    /// have been overridden (or which the UART doesn't have) are left as-is.
+    pub fn set_modem_line(&self, line: ModemLine, asserted: bool) {
+        let mut state = self.state.lock().unwrap();
+        if self.drives(&state, line) {
+            state.set_modem_line(line, asserted, &self.interrupt_bus);
+        }
+    }
+
+    /// Same as [PeerHandle::set_modem_line], except the change isn't flagged
+    /// to the guest. Used to set up the lines before the guest starts running.
+    pub fn init_modem_line(&self, line: ModemLine, asserted: bool) {
+        let mut state = self.state.lock().unwrap();
+        if self.drives(&state, line) {
+            state.init_modem_line(line, asserted);
+        }
+    }
+
+    /// Pin one of the UART's modem status lines to the specified level,
+    /// ignoring any changes subsequently made by the backend. Intended to be
+    /// called before the guest starts running.
+    pub fn override_modem_line(&self, line: ModemLine, asserted: bool) {
+        let mut state = self.state.lock().unwrap();
+        if self.drives(&state, line) {
+            state.init_modem_line(line, asserted);
+            state.modem_overrides |= line.mask();
+        }
+    }
+
+    /// Set the model used to drive the UART's CTS line.
+    pub fn set_cts_model(&self, model: CtsModel) {
+        let mut state = self.state.lock().unwrap();
+        state.cts_model = model;
+        state.set_cts(true, &self.interrupt_bus);
+    }
+
+    /// Send a character to the UART, forcing a framing and / or parity error
+    /// as though it had been corrupted on the wire.
+    ///
+    /// NOTE: characters sent via the PeerHandle are queued separately from
+    /// those sent via the backend's data channel. Backends which inject errors
+    /// should send _all_ their data through here to preserve ordering.
+    pub fn send_with_errors(&self, byte: u8, framing: bool, parity: bool) {
+        let mut errors = 0;
+        if framing {
+            errors |= RX_FE;
+        }
+        if parity {
+            errors |= RX_PE;
+        }
+        // the input thread only exits once the UART is dropped
+        let _ = self.input.send(RxEvent::Char { byte, errors });
+    }
+
+    /// Send a character to the UART.
+    pub fn send(&self, byte: u8) {
+        self.send_with_errors(byte, false, false)
+    }
+
+    /// Hold the UART's rx line low for a character time, which the UART
+    /// receives as a break.
+    pub fn send_break(&self) {
+        let _ = self.input.send(RxEvent::Break);
+    }
+}
+
 /// UART device implementing all behavior shared by UARTs 1, 2, and 3 on the
-/// TS-7200. i.e: this device doesn't include any UART-specific functionality,
-/// such as HDCL or Modem controls.
+/// TS-7200, along with UART1's modem control registers. i.e: this device
+/// doesn't include any HDLC functionality.
 ///
 /// As described in sections 14, 15, and 16 of the EP93xx User's Guide.
 #[derive(Debug)]
 pub struct Uart {
     label: &'static str,
+    clock_enabled: bool,
+    has_uart1_regs: bool,
     state: Arc<Mutex<State>>,
     interrupt_bus: chan::Sender<(Interrupt, bool)>,
     worker: UartWorker,
@@ -475,6 +1187,8 @@ impl Uart {
         let worker = UartWorker::new(label, state.clone(), interrupt_bus.clone());
         Uart {
             label,
+            clock_enabled: true,
+            has_uart1_regs: false,
             state,
             interrupt_bus,
             worker,
@@ -509,6 +1223,90 @@ impl Uart {
         Ok(ret)
     }
 
+    /// Reset the UART's registers, keeping any attached backend.
+    pub fn reset(&mut self) {
+        self.state.lock().unwrap().reset_hle();
+        self.clock_enabled = true;
+    }
+
+    /// Gate the UART's clock (as controlled by the UxEN bits in the Syscon's
+    /// DeviceCfg register).
+    pub fn set_clock_enabled(&mut self, enabled: bool) {
+        self.clock_enabled = enabled;
+    }
+
+    fn check_clock_enabled(&self) -> MemResult<()> {
+        if self.clock_enabled {
+            Ok(())
+        } else {
+            Err(ContractViolation {
+                msg: "Accessed UART while its clock is disabled (check syscon DeviceCfg)"
+                    .to_string(),
+                severity: log::Level::Error,
+                stub_val: None,
+            })
+        }
+    }
+
+    /// Set the frequency of the UART's reference clock.
+    pub fn set_uartclk_hz(&mut self, hz: u64) {
+        let mut state = self.state.lock().unwrap();
+        if state.uartclk_hz != hz {
+            state.uartclk_hz = hz;
+            state.update_linctrl();
+            state.report_baud();
+        }
+    }
+
+    /// Map the modem control / status and HDLC registers (which are only
+    /// present on UART1).
+    pub fn enable_uart1_regs(&mut self) {
+        self.has_uart1_regs = true;
+    }
+
+    /// Return a handle with which the UART's peer can drive its modem status
+    /// lines.
+    pub fn peer_handle(&self) -> PeerHandle {
+        PeerHandle {
+            state: self.state.clone(),
+            interrupt_bus: self.interrupt_bus.clone(),
+            input: self.worker.rx_event_chan.clone(),
+            has_modem_lines: self.has_uart1_regs,
+        }
+    }
+
+    /// Set the model used to drive the UART's CTS line.
+    pub fn set_cts_model(&mut self, model: CtsModel) {
+        self.peer_handle().set_cts_model(model)
+    }
+
+    /// Whether the UART is requesting the DMA controller write another byte
+    /// into its tx FIFO.
+    pub fn dma_tx_request(&self) -> bool {
+        let state = self.state.lock().unwrap();
+        self.clock_enabled
+            && state.dma_ctrl & DMACTRL_TXDMAE != 0
+            && state.tx_buf_size < state.fifo_size
+    }
+
+    /// Whether the UART is requesting the DMA controller read a byte out of
+    /// its rx FIFO. When DMAONERR is set, requests are withheld while the
+    /// next character has a receive error, leaving it for the CPU to handle.
+    pub fn dma_rx_request(&self) -> bool {
+        let state = self.state.lock().unwrap();
+        match state.rx_buf.front() {
+            Some(c) if self.clock_enabled && state.dma_ctrl & DMACTRL_RXDMAE != 0 => {
+                state.dma_ctrl & DMACTRL_DMAONERR == 0 || *c >> 8 == 0
+            }
+            _ => false,
+        }
+    }
+
+    /// Record all traffic crossing the UART to a transcript.
+    pub fn set_transcript(&mut self, transcript: Option<TranscriptHandle>) {
+        self.state.lock().unwrap().transcript = transcript;
+    }
+
     /// HACK: sets the UART to have an infinite RX FIFO
     pub fn hack_inf_uart_rx(&mut self, active: bool) {
         self.state.lock().unwrap().hack_inf_uart_rx = active;
@@ -541,6 +1339,13 @@ impl Device for Uart {
             0x1C => "IntIDIntClr",
             0x20 => "IrLowPwrCntr",
             0x28 => "DMACtrl",
+            0x100 if self.has_uart1_regs => "MdmCtl",
+            0x104 if self.has_uart1_regs => "MdmSts",
+            0x20C if self.has_uart1_regs => "HDLCCtl",
+            0x210 if self.has_uart1_regs => "HDLCAddMtchVal",
+            0x214 if self.has_uart1_regs => "HDLCAddMask",
+            0x218 if self.has_uart1_regs => "HDLCRXInfoBuf",
+            0x21C if self.has_uart1_regs => "HDLCSts",
             _ => return Probe::Unmapped,
         };
         Probe::Register(reg)
@@ -549,13 +1354,18 @@ impl Device for Uart {
 
 impl Memory for Uart {
     fn r32(&mut self, offset: u32) -> MemResult<u32> {
+        self.check_clock_enabled()?;
+
         let mut state = self.state.lock().unwrap();
         match offset {
             // data (8-bit)
             0x00 => {
                 // If the buffer is empty return a dummy value
                 let val = match state.rx_buf.pop_front() {
-                    Some(v) => v as u32,
+                    Some(v) => {
+                        state.rx_sts = v >> 8;
+                        v as u32
+                    }
                     None => {
                         return Err(ContractViolation {
                             msg: "Reading from empty UART FIFO".to_string(),
@@ -572,7 +1382,7 @@ impl Memory for Uart {
                 Ok(val)
             }
             // read status
-            0x04 => Ok(if state.overrun { 8 } else { 0 }),
+            0x04 => Ok(state.rx_sts as u32 | if state.overrun { 8 } else { 0 }),
             // line control high
             0x08 => Ok(state.linctrl[0]),
             // line control mid
@@ -620,22 +1430,41 @@ impl Memory for Uart {
                 }
                 if state.busy {
                     result |= 0x8;
-                } else {
-                    // XXX: set cts when not sending data
-                    // TODO: determine a better way to do cts
+                }
+                if state.modem_line(ModemLine::Dcd) {
+                    result |= 0x4;
+                }
+                if state.modem_line(ModemLine::Dsr) {
+                    result |= 0x2;
+                }
+                if state.modem_line(ModemLine::Cts) {
                     result |= 0x1;
                 }
                 Ok(result)
             }
             // interrupt identification and clear register
             0x1C => Ok(state.get_int_id() as u32),
+            // IrDA low-power counter
+            0x20 => Ok(state.ilpr),
             // dma control
-            0x28 => Err(Unimplemented),
+            0x28 => Ok(state.dma_ctrl),
+            // modem control
+            0x100 if self.has_uart1_regs => Ok(state.modem_ctl),
+            // modem status
+            0x104 if self.has_uart1_regs => Ok(state.modem_sts as u32),
+            // HDLC control / address match
+            0x20C | 0x210 | 0x214 if self.has_uart1_regs => {
+                Ok(state.hdlc[(offset as usize - 0x20C) / 4])
+            }
+            // HDLC receive info buffer / status (no frames are ever received)
+            0x218 | 0x21C if self.has_uart1_regs => Ok(0),
             _ => Err(Unexpected),
         }
     }
 
     fn w32(&mut self, offset: u32, val: u32) -> MemResult<()> {
+        self.check_clock_enabled()?;
+
         let mut state = self.state.lock().unwrap();
         match offset {
             // data (8-bit)
@@ -658,16 +1487,26 @@ impl Memory for Uart {
             // write status
             0x04 => {
                 state.overrun = false;
+                state.rx_sts = 0;
                 state.update_interrupts(&self.interrupt_bus);
                 Ok(())
             }
             // line control high
             0x08 => {
+                if (state.linctrl[0] ^ val) & LINCTRL_BRK != 0 {
+                    state.record(TranscriptEvent::Break(val & LINCTRL_BRK != 0));
+                }
                 state.linctrl_latched = false;
                 state.linctrl_latch[0] = val;
 
+                let old_bauddiv = state.bauddiv();
                 state.linctrl = state.linctrl_latch;
                 state.update_linctrl();
+                // guests commonly rewrite LinCtrlHigh on its own (e.g: to send
+                // a break), so only report actual changes to the baud rate
+                if state.bauddiv() != old_bauddiv {
+                    state.report_baud();
+                }
                 state.update_interrupts(&self.interrupt_bus);
                 Ok(())
             }
@@ -693,16 +1532,104 @@ impl Memory for Uart {
             0x18 => Err(InvalidAccess),
             // interrupt identification and clear register
             0x1C => {
-                if state.cts_change {
-                    trace!("{} clearing cts interrupt", self.label);
+                if state.modem_sts & 0x0f != 0 {
+                    trace!("{} clearing modem status interrupt", self.label);
                 }
-                state.cts_change = false;
+                state.modem_sts &= 0xf0;
                 state.update_interrupts(&self.interrupt_bus);
                 Ok(())
             }
+            // IrDA low-power counter
+            0x20 => {
+                state.ilpr = val & 0xff;
+                Err(ContractViolation {
+                    msg: "The UART's IrDA SIR encoder isn't wired up on the TS-7200".to_string(),
+                    severity: log::Level::Warn,
+                    stub_val: None,
+                })
+            }
             // dma control
-            0x28 => Err(Unimplemented),
+            0x28 => Ok(state.dma_ctrl = val & 0x7),
+            // modem control
+            0x100 if self.has_uart1_regs => {
+                // DTR and RTS are stored, but aren't forwarded to the peer
+                state.modem_ctl = val & 0x3;
+                Ok(())
+            }
+            // modem status
+            0x104 if self.has_uart1_regs => Err(InvalidAccess),
+            // HDLC control
+            0x20C if self.has_uart1_regs => {
+                state.hdlc[0] = val;
+                if val == 0 {
+                    return Ok(());
+                }
+                Err(ContractViolation {
+                    msg:
+                        "HDLC framing isn't supported by the emulator (and isn't used by anything \
+                          wired to the TS-7200's UART1)"
+                            .to_string(),
+                    severity: log::Level::Warn,
+                    stub_val: None,
+                })
+            }
+            // HDLC address match
+            0x210 | 0x214 if self.has_uart1_regs => {
+                Ok(state.hdlc[(offset as usize - 0x20C) / 4] = val)
+            }
+            // HDLC receive info buffer / status
+            0x218 | 0x21C if self.has_uart1_regs => Err(InvalidAccess),
             _ => Err(Unexpected),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A UART at 115200 8N1, talking to a peer at the specified baud rate
+    fn state_with_peer_baud(peer_baud: u64) -> State {
+        let mut state = State::new_hle("test", interrupts::UART1);
+        assert_eq!(state.baud, 115200);
+        state.peer_baud = Some(peer_baud);
+        state
+    }
+
+    #[test]
+    fn resample_matching_baud() {
+        let state = state_with_peer_baud(115200);
+        for &b in &[0x00, 0x55, 0xa5, 0xff] {
+            assert_eq!(state.resample(b), (b, 0));
+        }
+    }
+
+    #[test]
+    fn resample_slower_peer() {
+        // every bit the peer sends is sampled twice, so the (low) start bit
+        // swallows the first data bit, and the stop bit comes too late
+        let state = state_with_peer_baud(57600);
+        assert_eq!(state.resample(0x00), (0x00, RX_FE));
+        assert_eq!(state.resample(0xff), (0xfe, 0));
+    }
+
+    #[test]
+    fn resample_faster_peer() {
+        // only every other bit is sampled, and the frame is over halfway
+        // through the guest's character (leaving the line idling high)
+        let state = state_with_peer_baud(230400);
+        assert_eq!(state.resample(0x00), (0xf8, 0));
+        assert_eq!(state.resample(0xff), (0xff, 0));
+    }
+
+    #[test]
+    fn resample_zero_baud() {
+        // UARTCLK is too slow for the divisor to give a usable baud rate
+        let mut state = state_with_peer_baud(115200);
+        state.uartclk_hz = 16;
+        state.update_linctrl();
+        assert_eq!(state.baud, 0);
+        assert!(state.baud_mismatch());
+        assert_eq!(state.resample(0x55), (0, RX_FE));
+    }
+}
@@ -0,0 +1,273 @@
+use std::str::FromStr;
+
+const ESC: u8 = 0x1b;
+const BS: u8 = 0x08;
+const DEL: u8 = 0x7f;
+
+/// What the backspace key is sent as
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Backspace {
+    /// ASCII BS (0x08)
+    Bs,
+    /// ASCII DEL (0x7f)
+    Del,
+    /// Whatever the terminal sends
+    Raw,
+}
+
+/// How newlines are translated
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Newline {
+    /// Passed through as-is
+    Raw,
+    Cr,
+    Lf,
+    CrLf,
+}
+
+/// What the arrow keys are sent as
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Arrows {
+    /// ANSI / VT100 cursor keys (e.g: `ESC [ A`)
+    Ansi,
+    /// Application cursor keys (e.g: `ESC O A`)
+    Ss3,
+    /// Arrow keys are ignored
+    Drop,
+    /// Whatever the terminal sends
+    Raw,
+}
+
+/// Translations applied to data passing between the terminal and a UART via
+/// the stdio backend.
+///
+/// Accepts a preset name, followed by a comma separated list of `key=val`
+/// overrides:
+///
+/// - presets: `gtkterm` (default), `minicom`, `raw`
+/// - `bs=<bs|del|raw>`: what the backspace key is sent as
+/// - `enter=<cr|lf|crlf|raw>`: what the enter key is sent as
+/// - `onl=<lf|crlf|raw>`: what newlines (LF) output by the UART are written as
+/// - `arrows=<ansi|ss3|drop|raw>`: what the arrow keys are sent as
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Keymap {
+    backspace: Backspace,
+    enter: Newline,
+    output_newline: Newline,
+    arrows: Arrows,
+}
+
+impl Keymap {
+    /// Matches the lab's gtkterm setup, which sends backspace as BS.
+    ///
+    /// Like gtkterm, arrow keys are always sent as ANSI cursor keys, even if
+    /// the host terminal is in application cursor mode.
+    const GTKTERM: Keymap = Keymap {
+        backspace: Backspace::Bs,
+        enter: Newline::Cr,
+        output_newline: Newline::Raw,
+        arrows: Arrows::Ansi,
+    };
+
+    /// Sends backspace as DEL, arrow keys as ANSI cursor keys, and writes
+    /// newlines as CRLF (i.e: minicom with "Add carriage return" enabled).
+    const MINICOM: Keymap = Keymap {
+        backspace: Backspace::Del,
+        enter: Newline::Cr,
+        output_newline: Newline::CrLf,
+        arrows: Arrows::Ansi,
+    };
+
+    /// No translation whatsoever.
+    const RAW: Keymap = Keymap {
+        backspace: Backspace::Raw,
+        enter: Newline::Raw,
+        output_newline: Newline::Raw,
+        arrows: Arrows::Raw,
+    };
+
+    /// Return a mapper for data read from the terminal.
+    pub(super) fn input_mapper(&self) -> InputMapper {
+        InputMapper {
+            keymap: *self,
+            pending: Vec::new(),
+        }
+    }
+
+    /// Translate a byte output by the UART, appending the result to `out`.
+    pub(super) fn map_output(&self, b: u8, out: &mut Vec<u8>) {
+        match (b, self.output_newline) {
+            (b'\n', Newline::CrLf) => out.extend_from_slice(b"\r\n"),
+            _ => out.push(b),
+        }
+    }
+}
+
+impl Default for Keymap {
+    fn default() -> Keymap {
+        Keymap::GTKTERM
+    }
+}
+
+/// Translates keys read from the terminal, buffering partial escape sequences.
+pub(super) struct InputMapper {
+    keymap: Keymap,
+    // partial arrow key escape sequence
+    pending: Vec<u8>,
+}
+
+impl InputMapper {
+    /// Translate a byte read from the terminal, appending the result to `out`.
+    pub fn push(&mut self, b: u8, out: &mut Vec<u8>) {
+        if self.keymap.arrows != Arrows::Raw {
+            match (self.pending.as_slice(), b) {
+                ([], ESC) | ([ESC], b'[') | ([ESC], b'O') => {
+                    self.pending.push(b);
+                    return;
+                }
+                ([ESC, _], b'A'..=b'D') => {
+                    self.pending.clear();
+                    match self.keymap.arrows {
+                        Arrows::Ansi => out.extend_from_slice(&[ESC, b'[', b]),
+                        Arrows::Ss3 => out.extend_from_slice(&[ESC, b'O', b]),
+                        Arrows::Drop | Arrows::Raw => {}
+                    }
+                    return;
+                }
+                ([], _) => {}
+                _ => {
+                    self.flush(out);
+                    // the ESC may be the start of another escape sequence
+                    if b == ESC {
+                        self.pending.push(b);
+                        return;
+                    }
+                }
+            }
+        }
+
+        match b {
+            BS | DEL => out.push(match self.keymap.backspace {
+                Backspace::Bs => BS,
+                Backspace::Del => DEL,
+                Backspace::Raw => b,
+            }),
+            b'\r' => match self.keymap.enter {
+                Newline::Raw | Newline::Cr => out.push(b'\r'),
+                Newline::Lf => out.push(b'\n'),
+                Newline::CrLf => out.extend_from_slice(b"\r\n"),
+            },
+            _ => out.push(b),
+        }
+    }
+
+    /// Pass through any partial escape sequence as-is. Terminals send escape
+    /// sequences in a single write, so this should be called once the current
+    /// batch of input has been processed (e.g: so a lone ESC isn't held back).
+    pub fn flush(&mut self, out: &mut Vec<u8>) {
+        out.append(&mut self.pending);
+    }
+}
+
+impl FromStr for Keymap {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Keymap, &'static str> {
+        let mut opts = s.split(',');
+        let mut keymap = match opts.next().unwrap() {
+            "gtkterm" => Keymap::GTKTERM,
+            "minicom" => Keymap::MINICOM,
+            "raw" => Keymap::RAW,
+            _ => return Err("invalid keymap preset (expected gtkterm, minicom, or raw)"),
+        };
+
+        for opt in opts {
+            let mut opt = opt.split('=');
+            let key = opt.next().unwrap();
+            let val = opt.next().ok_or("expected to find `key=val`")?;
+            match key {
+                "bs" => {
+                    keymap.backspace = match val {
+                        "bs" => Backspace::Bs,
+                        "del" => Backspace::Del,
+                        "raw" => Backspace::Raw,
+                        _ => return Err("expected bs to be `bs`, `del`, or `raw`"),
+                    }
+                }
+                "enter" => {
+                    keymap.enter = match val {
+                        "cr" => Newline::Cr,
+                        "lf" => Newline::Lf,
+                        "crlf" => Newline::CrLf,
+                        "raw" => Newline::Raw,
+                        _ => return Err("expected enter to be `cr`, `lf`, `crlf`, or `raw`"),
+                    }
+                }
+                "onl" => {
+                    keymap.output_newline = match val {
+                        "lf" | "raw" => Newline::Raw,
+                        "crlf" => Newline::CrLf,
+                        _ => return Err("expected onl to be `lf`, `crlf`, or `raw`"),
+                    }
+                }
+                "arrows" => {
+                    keymap.arrows = match val {
+                        "ansi" => Arrows::Ansi,
+                        "ss3" => Arrows::Ss3,
+                        "drop" => Arrows::Drop,
+                        "raw" => Arrows::Raw,
+                        _ => return Err("expected arrows to be `ansi`, `ss3`, `drop`, or `raw`"),
+                    }
+                }
+                _ => return Err("invalid keymap option"),
+            }
+        }
+        Ok(keymap)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keymap_from_str() {
+        assert_eq!("gtkterm".parse(), Ok(Keymap::GTKTERM));
+        assert_eq!("raw".parse(), Ok(Keymap::RAW));
+        assert_eq!(
+            "minicom,bs=bs,enter=crlf,onl=lf,arrows=ss3".parse(),
+            Ok(Keymap {
+                backspace: Backspace::Bs,
+                enter: Newline::CrLf,
+                output_newline: Newline::Raw,
+                arrows: Arrows::Ss3,
+            })
+        );
+
+        assert!("vt100".parse::<Keymap>().is_err());
+        assert!("raw,bs".parse::<Keymap>().is_err());
+        assert!("raw,bs=tab".parse::<Keymap>().is_err());
+        assert!("raw,tab=bs".parse::<Keymap>().is_err());
+    }
+
+    #[test]
+    fn input_mapper_arrows() {
+        let keymap: Keymap = "raw,arrows=ss3".parse().unwrap();
+        let mut mapper = keymap.input_mapper();
+        let mut out = Vec::new();
+        for &b in b"\x1b[Ax\x1b\x1b[B\x1b\x1bOC" {
+            mapper.push(b, &mut out);
+        }
+        mapper.flush(&mut out);
+        assert_eq!(out, b"\x1bOAx\x1b\x1bOB\x1b\x1bOC");
+
+        // application cursor keys are sent as plain ANSI cursor keys
+        let mut mapper = Keymap::GTKTERM.input_mapper();
+        let mut out = Vec::new();
+        for &b in b"\x1bOD\x1b[C\x1b" {
+            mapper.push(b, &mut out);
+        }
+        mapper.flush(&mut out);
+        assert_eq!(out, b"\x1b[D\x1b[C\x1b");
+    }
+}
@@ -0,0 +1,382 @@
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crossbeam_channel::{self as chan, select};
+
+use super::ReaderTask;
+
+/// Number of sensor modules (A through E) wired up on the CS 452 track
+const NUM_MODULES: usize = 5;
+
+/// How long a switch's solenoid can be left energized before it's at risk of
+/// burning out.
+const SOLENOID_MAX_ON: Duration = Duration::from_millis(500);
+
+const CMD_SOLENOID_OFF: u8 = 0x20;
+const CMD_SWITCH_STRAIGHT: u8 = 0x21;
+const CMD_SWITCH_CURVED: u8 = 0x22;
+const CMD_GO: u8 = 0x60;
+const CMD_STOP: u8 = 0x61;
+const CMD_RESET_MODE_OFF: u8 = 0x80;
+const CMD_RESET_MODE_ON: u8 = 0xc0;
+
+/// A timeline of sensor contacts, used to simulate trains moving around the
+/// track.
+///
+/// Timelines consist of one `<ms> <sensor>` entry per line (blank lines and
+/// lines starting with `#` are ignored), where `<ms>` is the time since the
+/// backend was attached, and `<sensor>` is a module letter followed by a
+/// contact number (e.g: `A5`, `E16`).
+#[derive(Debug, Default)]
+pub struct Timeline {
+    // (time since start, module index, contact index), sorted by time
+    contacts: Vec<(Duration, usize, usize)>,
+}
+
+impl Timeline {
+    /// Parse a timeline, returning a descriptive error on failure.
+    pub fn parse(src: &str) -> Result<Timeline, String> {
+        let mut contacts = Vec::new();
+        for (i, line) in src.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let err = |msg: &str| format!("line {}: {}", i + 1, msg);
+
+            let mut s = line.split_whitespace();
+            let ms = s
+                .next()
+                .unwrap()
+                .parse()
+                .map_err(|_| err("invalid time (expected ms)"))?;
+            let sensor = s.next().ok_or_else(|| err("expected a sensor"))?;
+            if s.next().is_some() {
+                return Err(err("expected `<ms> <sensor>`"));
+            }
+
+            let module = match sensor.as_bytes()[0].to_ascii_uppercase() {
+                c @ b'A'..=b'E' => (c - b'A') as usize,
+                _ => return Err(err("sensor module must be between A and E")),
+            };
+            let contact = match sensor[1..].parse::<usize>() {
+                Ok(n @ 1..=16) => n - 1,
+                _ => return Err(err("sensor number must be between 1 and 16")),
+            };
+
+            contacts.push((Duration::from_millis(ms), module, contact));
+        }
+
+        contacts.sort_by_key(|&(t, _, _)| t);
+        Ok(Timeline { contacts })
+    }
+}
+
+/// State of the simulated Märklin 6051 controller
+struct Marklin {
+    label: String,
+    running: bool,
+    reset_mode: bool,
+    // latched sensor contacts, in the format they are reported in (i.e: the
+    // MSB corresponds to contact 1)
+    modules: [u16; NUM_MODULES],
+    solenoid_on_since: Option<Instant>,
+    // first byte of a two byte command
+    pending: Option<u8>,
+}
+
+impl Marklin {
+    fn new(label: String) -> Marklin {
+        Marklin {
+            label,
+            running: true,
+            reset_mode: true,
+            modules: [0; NUM_MODULES],
+            solenoid_on_since: None,
+            pending: None,
+        }
+    }
+
+    /// Process a byte received from the UART, returning any response.
+    fn handle_byte(&mut self, b: u8) -> Vec<u8> {
+        if let Some(cmd) = self.pending.take() {
+            self.handle_two_byte_cmd(cmd, b);
+            return Vec::new();
+        }
+
+        match b {
+            0x00..=0x1f | CMD_SWITCH_STRAIGHT | CMD_SWITCH_CURVED => self.pending = Some(b),
+            CMD_SOLENOID_OFF => {
+                debug!("{}: solenoid off", self.label);
+                self.solenoid_on_since = None;
+            }
+            CMD_GO => {
+                info!("{}: go", self.label);
+                self.running = true;
+            }
+            CMD_STOP => {
+                info!("{}: stop", self.label);
+                self.running = false;
+            }
+            CMD_RESET_MODE_OFF => self.reset_mode = false,
+            CMD_RESET_MODE_ON => self.reset_mode = true,
+            0x81..=0x9f => {
+                let n = (b - 0x80) as usize;
+                trace!("{}: dumping sensor modules 1-{}", self.label, n);
+                return (0..n).flat_map(|m| self.report(m).to_vec()).collect();
+            }
+            0xc1..=0xdf => {
+                let m = (b - 0xc1) as usize;
+                trace!("{}: dumping sensor module {}", self.label, m + 1);
+                return self.report(m).to_vec();
+            }
+            _ => warn!("{}: unknown command byte {:#04x?}", self.label, b),
+        }
+        Vec::new()
+    }
+
+    fn handle_two_byte_cmd(&mut self, cmd: u8, arg: u8) {
+        if !self.running {
+            warn!(
+                "{}: ignoring command {:#04x?} {:#04x?} (controller is stopped)",
+                self.label, cmd, arg
+            );
+            return;
+        }
+
+        match cmd {
+            CMD_SWITCH_STRAIGHT | CMD_SWITCH_CURVED => {
+                let dir = if cmd == CMD_SWITCH_STRAIGHT { 'S' } else { 'C' };
+                info!("{}: switch {} -> {}", self.label, arg, dir);
+                self.solenoid_on_since.get_or_insert_with(Instant::now);
+            }
+            _ => {
+                if !(1..=80).contains(&arg) {
+                    warn!("{}: invalid train number {}", self.label, arg);
+                    return;
+                }
+                match cmd & 0xf {
+                    15 => info!("{}: train {} reverse", self.label, arg),
+                    speed => info!(
+                        "{}: train {} speed {}{}",
+                        self.label,
+                        arg,
+                        speed,
+                        if cmd & 0x10 != 0 { " (lights on)" } else { "" }
+                    ),
+                }
+            }
+        }
+    }
+
+    /// Report the state of a sensor module, clearing its contacts when in
+    /// reset mode.
+    fn report(&mut self, module: usize) -> [u8; 2] {
+        let contacts = match self.modules.get_mut(module) {
+            Some(contacts) => contacts,
+            // nothing is plugged in to the remaining module slots
+            None => return [0, 0],
+        };
+        let report = [(*contacts >> 8) as u8, *contacts as u8];
+        if self.reset_mode {
+            *contacts = 0;
+        }
+        report
+    }
+
+    fn trigger(&mut self, module: usize, contact: usize) {
+        trace!(
+            "{}: sensor {}{} triggered",
+            self.label,
+            (b'A' + module as u8) as char,
+            contact + 1
+        );
+        self.modules[module] |= 0x8000 >> contact;
+    }
+}
+
+/// Spawn a thread which simulates a Märklin 6051 train controller attached
+/// to a UART, triggering sensors as specified by `timeline`.
+///
+/// NOTE: the controller's CTS behavior is modeled by the UART itself (see
+/// `CtsModel::Marklin`).
+pub fn marklin_to_chans(
+    thread_label: String,
+    timeline: Timeline,
+    tx: chan::Sender<u8>,
+    rx: chan::Receiver<u8>,
+) -> ReaderTask {
+    let (exit_tx, exit_rx) = chan::bounded(1);
+
+    let mut marklin = Marklin::new(thread_label.clone());
+
+    let thread = move || {
+        let start = Instant::now();
+        let mut contacts = timeline.contacts.into_iter().peekable();
+
+        loop {
+            let now = Instant::now();
+            while let Some(&(t, module, contact)) = contacts.peek() {
+                if start + t > now {
+                    break;
+                }
+                marklin.trigger(module, contact);
+                contacts.next();
+            }
+
+            if let Some(since) = marklin.solenoid_on_since {
+                if now - since >= SOLENOID_MAX_ON {
+                    warn!(
+                        "{}: solenoid left on for over {:?} (did you forget to send {:#04x?}?)",
+                        marklin.label, SOLENOID_MAX_ON, CMD_SOLENOID_OFF
+                    );
+                    marklin.solenoid_on_since = None;
+                }
+            }
+
+            let deadlines = [
+                contacts.peek().map(|&(t, _, _)| start + t),
+                marklin
+                    .solenoid_on_since
+                    .map(|since| since + SOLENOID_MAX_ON),
+            ];
+            let timeout = match deadlines.iter().flatten().min() {
+                Some(deadline) => chan::after(deadline.saturating_duration_since(now)),
+                None => chan::never(),
+            };
+
+            select! {
+                recv(rx) -> b => match b {
+                    Ok(b) => {
+                        for b in marklin.handle_byte(b) {
+                            if tx.send(b).is_err() {
+                                return;
+                            }
+                        }
+                    }
+                    Err(chan::RecvError) => return,
+                },
+                recv(exit_rx) -> _ => return,
+                recv(timeout) -> _ => {},
+            }
+        }
+    };
+
+    let handle = thread::Builder::new()
+        .name(format!("{} - Marklin", thread_label))
+        .spawn(thread)
+        .expect("failed to spawn thread");
+
+    ReaderTask::new(handle, exit_tx)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timeline_parse() {
+        let timeline = Timeline::parse("# comment\n\n1500 c13\n  200 A1  \n900 E16\n").unwrap();
+        assert_eq!(
+            timeline.contacts,
+            [
+                (Duration::from_millis(200), 0, 0),
+                (Duration::from_millis(900), 4, 15),
+                (Duration::from_millis(1500), 2, 12),
+            ]
+        );
+
+        for bad in &[
+            "A1",
+            "100",
+            "-5 A1",
+            "100 F1",
+            "100 A0",
+            "100 A17",
+            "100 A1 B2",
+        ] {
+            assert!(Timeline::parse(bad).is_err(), "{:?}", bad);
+        }
+    }
+
+    fn with_contacts() -> Marklin {
+        let mut marklin = Marklin::new("test".into());
+        marklin.trigger(0, 0);
+        marklin.trigger(1, 15);
+        marklin.trigger(4, 8);
+        marklin
+    }
+
+    #[test]
+    fn sensor_dump() {
+        let mut marklin = with_contacts();
+        assert_eq!(
+            marklin.handle_byte(0x85),
+            [0x80, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x80]
+        );
+        // reset mode is on by default, so the dump cleared every contact
+        assert_eq!(marklin.handle_byte(0x85), [0; 10]);
+
+        // single module dumps
+        let mut marklin = with_contacts();
+        assert_eq!(marklin.handle_byte(0xc2), [0x00, 0x01]);
+        assert_eq!(marklin.handle_byte(0xc2), [0x00, 0x00]);
+        // modules that aren't plugged in never report anything
+        assert_eq!(marklin.handle_byte(0xc6), [0x00, 0x00]);
+    }
+
+    #[test]
+    fn reset_mode() {
+        let mut marklin = with_contacts();
+        assert!(marklin.handle_byte(CMD_RESET_MODE_OFF).is_empty());
+        assert_eq!(marklin.handle_byte(0xc1), [0x80, 0x00]);
+        assert_eq!(marklin.handle_byte(0xc1), [0x80, 0x00]);
+
+        assert!(marklin.handle_byte(CMD_RESET_MODE_ON).is_empty());
+        assert_eq!(marklin.handle_byte(0xc1), [0x80, 0x00]);
+        assert_eq!(marklin.handle_byte(0xc1), [0x00, 0x00]);
+    }
+
+    #[test]
+    fn two_byte_framing() {
+        let mut marklin = with_contacts();
+
+        // the second byte of a command is never interpreted as a command
+        assert!(marklin.handle_byte(0x0e).is_empty());
+        assert!(marklin.handle_byte(0x85).is_empty());
+        assert_eq!(marklin.pending, None);
+        assert_eq!(marklin.modules[0], 0x8000);
+
+        assert!(marklin.handle_byte(CMD_SWITCH_CURVED).is_empty());
+        assert_eq!(marklin.pending, Some(CMD_SWITCH_CURVED));
+        assert!(marklin.solenoid_on_since.is_none());
+        assert!(marklin.handle_byte(0x99).is_empty());
+        assert_eq!(marklin.pending, None);
+        assert!(marklin.solenoid_on_since.is_some());
+
+        assert!(marklin.handle_byte(CMD_SOLENOID_OFF).is_empty());
+        assert!(marklin.solenoid_on_since.is_none());
+    }
+
+    #[test]
+    fn stopped() {
+        let mut marklin = with_contacts();
+        assert!(marklin.handle_byte(CMD_STOP).is_empty());
+        assert!(!marklin.running);
+
+        // two byte commands are still framed, but have no effect
+        assert!(marklin.handle_byte(CMD_SWITCH_STRAIGHT).is_empty());
+        assert!(marklin.handle_byte(0x05).is_empty());
+        assert_eq!(marklin.pending, None);
+        assert!(marklin.solenoid_on_since.is_none());
+
+        // sensor dumps still work
+        assert_eq!(marklin.handle_byte(0xc1), [0x80, 0x00]);
+
+        assert!(marklin.handle_byte(CMD_GO).is_empty());
+        assert!(marklin.handle_byte(CMD_SWITCH_STRAIGHT).is_empty());
+        assert!(marklin.handle_byte(0x05).is_empty());
+        assert!(marklin.solenoid_on_since.is_some());
+    }
+}
@@ -1,44 +1,247 @@
-use std::io::{Read, Write};
-use std::thread::{self, JoinHandle};
+use std::io::{self, Read, Write};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::thread;
 
-use crossbeam_channel as chan;
+use crossbeam_channel::{self as chan, select};
 
+use super::{ReaderTask, WriterTask};
+
+mod keymap;
+mod marklin;
+mod pty;
+mod script;
+mod server;
 mod stdio;
-pub use stdio::stdio_to_chans;
+pub use keymap::Keymap;
+pub use marklin::{marklin_to_chans, Timeline};
+pub use pty::open_pty;
+pub use script::{script_to_chans, Script};
+pub use server::{server_to_chans, Listener, UnixSocketListener};
+pub use stdio::{stdio_to_chans, HostCmd};
+
+/// How often blocked reader / writer threads check whether they've been
+/// cancelled
+const CANCEL_POLL_MS: i32 = 50;
+
+/// Block until `fd` has data available to read. Returns `false` if the reader
+/// was cancelled while waiting (i.e: `exit` was signalled or dropped).
+fn wait_readable(fd: RawFd, exit: &chan::Receiver<()>) -> bool {
+    wait_ready(fd, libc::POLLIN, exit)
+}
+
+/// Block until `fd` can be written to without blocking. Returns `false` if the
+/// writer was cancelled while waiting (i.e: `exit` was signalled or dropped).
+fn wait_writable(fd: RawFd, exit: &chan::Receiver<()>) -> bool {
+    wait_ready(fd, libc::POLLOUT, exit)
+}
+
+fn wait_ready(fd: RawFd, events: libc::c_short, exit: &chan::Receiver<()>) -> bool {
+    loop {
+        match exit.try_recv() {
+            Err(chan::TryRecvError::Empty) => {}
+            Ok(()) | Err(chan::TryRecvError::Disconnected) => return false,
+        }
+
+        let mut pollfd = libc::pollfd {
+            fd,
+            events,
+            revents: 0,
+        };
+        // SAFETY: pollfd is a valid pointer to a single pollfd struct
+        let ret = unsafe { libc::poll(&mut pollfd, 1, CANCEL_POLL_MS) };
+        match ret {
+            0 => continue,
+            // errors / hangups are reported by the subsequent read / write
+            _ if ret > 0 => return true,
+            _ => match io::Error::last_os_error().kind() {
+                io::ErrorKind::Interrupted => continue,
+                _ => return true,
+            },
+        }
+    }
+}
 
 /// Spawn a thread that continuously writes data from `reader` to `tx`.
+///
+/// The thread exits once `reader` hits EOF (or returns an error), or the
+/// returned [ReaderTask] is cancelled.
 pub fn reader_to_chan(
     thread_label: String,
-    reader: impl Read + Send + 'static,
+    mut reader: impl Read + AsRawFd + Send + 'static,
     tx: chan::Sender<u8>,
-) -> JoinHandle<()> {
+) -> ReaderTask {
+    let (exit_tx, exit_rx) = chan::bounded(1);
+
+    let label = thread_label.clone();
     let thread = move || {
-        for b in reader.bytes() {
-            let b = b.expect("io error");
-            tx.send(b).unwrap();
+        let mut buf = [0; 256];
+        while wait_readable(reader.as_raw_fd(), &exit_rx) {
+            let n = match reader.read(&mut buf) {
+                Ok(0) => return,
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                // treat any other error as a disconnect (e.g: a pty hangup)
+                Err(e) => {
+                    warn!("{} - read failed ({}), disconnecting", label, e);
+                    return;
+                }
+            };
+            for &b in &buf[..n] {
+                if tx.send(b).is_err() {
+                    return;
+                }
+            }
         }
     };
 
-    thread::Builder::new()
+    let handle = thread::Builder::new()
         .name(format!("{} - Reader", thread_label))
         .spawn(thread)
-        .expect("failed to spawn thread")
+        .expect("failed to spawn thread");
+
+    ReaderTask::new(handle, exit_tx)
 }
 
-/// Spawn a thread that continuously writes data from `writer` to `rx`.
+/// Spawn a thread that continuously writes data from `rx` to `writer`.
+///
+/// If `writer` is disconnected (e.g: the other end of a socket hangs up), any
+/// further data is discarded.
+///
+/// The thread exits once `rx` hangs up, or the returned [WriterTask] is
+/// cancelled.
 pub fn writer_to_chan(
     thread_label: String,
-    mut writer: impl Write + Send + 'static,
+    mut writer: impl Write + AsRawFd + Send + 'static,
     rx: chan::Receiver<u8>,
-) -> JoinHandle<()> {
+) -> WriterTask {
+    let (exit_tx, exit_rx) = chan::bounded(1);
+
+    let label = thread_label.clone();
     let thread = move || {
-        for b in rx.iter() {
-            writer.write_all(&[b]).expect("io error");
+        loop {
+            let b = select! {
+                recv(rx) -> b => match b {
+                    Ok(b) => b,
+                    Err(chan::RecvError) => return,
+                },
+                recv(exit_rx) -> _ => return,
+            };
+            // the other end may stop draining its data (e.g: a pty which
+            // nobody has open), which mustn't prevent the writer from being
+            // cancelled.
+            if !wait_writable(writer.as_raw_fd(), &exit_rx) {
+                return;
+            }
+            if let Err(e) = writer.write_all(&[b]) {
+                warn!("{} - disconnected ({}), discarding output", label, e);
+                break;
+            }
+        }
+
+        loop {
+            select! {
+                recv(rx) -> b => if b.is_err() {
+                    return;
+                },
+                recv(exit_rx) -> _ => return,
+            }
         }
     };
 
-    thread::Builder::new()
+    let handle = thread::Builder::new()
         .name(format!("{} - Writer", thread_label))
         .spawn(thread)
-        .expect("failed to spawn thread")
+        .expect("failed to spawn thread");
+
+    WriterTask::new(handle, exit_tx)
+}
+
+/// Spawn a thread which owns a group of reader tasks, cancelling all of them
+/// once the returned [ReaderTask] is cancelled.
+///
+/// Any data sent to `discard` (i.e: by readers whose input is being ignored)
+/// is dropped on the floor.
+pub fn fan_in(
+    thread_label: String,
+    tasks: Vec<ReaderTask>,
+    mut discard: chan::Receiver<u8>,
+) -> ReaderTask {
+    let (exit_tx, exit_rx) = chan::bounded(1);
+
+    let thread = move || {
+        loop {
+            select! {
+                recv(discard) -> b => {
+                    if b.is_err() {
+                        discard = chan::never();
+                    }
+                }
+                recv(exit_rx) -> _ => break,
+            }
+        }
+        for task in tasks {
+            task.cancel();
+        }
+    };
+
+    let handle = thread::Builder::new()
+        .name(format!("{} - Fan In", thread_label))
+        .spawn(thread)
+        .expect("failed to spawn thread");
+
+    ReaderTask::new(handle, exit_tx)
+}
+
+/// Spawn a thread which copies each byte from `rx` into every one of `sinks`,
+/// which are consumed by `tasks`.
+///
+/// `idle` tasks aren't fed any data, but are kept alive alongside the others
+/// (e.g: the output half of an input-only backend).
+///
+/// The thread exits once `rx` hangs up (after waiting for `tasks` to drain
+/// their sinks), or the returned [WriterTask] is cancelled.
+pub fn fan_out(
+    thread_label: String,
+    rx: chan::Receiver<u8>,
+    sinks: Vec<chan::Sender<u8>>,
+    tasks: Vec<WriterTask>,
+    idle: Vec<WriterTask>,
+) -> WriterTask {
+    let (exit_tx, exit_rx) = chan::bounded(1);
+
+    let thread = move || {
+        let cancelled = loop {
+            select! {
+                recv(rx) -> b => match b {
+                    Ok(b) => {
+                        for sink in &sinks {
+                            // a sink's writer may have exited on its own
+                            let _ = sink.send(b);
+                        }
+                    }
+                    Err(chan::RecvError) => break false,
+                },
+                recv(exit_rx) -> _ => break true,
+            }
+        };
+
+        drop(sinks);
+        for task in idle {
+            task.cancel();
+        }
+        for task in tasks {
+            if cancelled {
+                task.cancel();
+            } else {
+                task.join();
+            }
+        }
+    };
+
+    let handle = thread::Builder::new()
+        .name(format!("{} - Fan Out", thread_label))
+        .spawn(thread)
+        .expect("failed to spawn thread");
+
+    WriterTask::new(handle, exit_tx)
 }
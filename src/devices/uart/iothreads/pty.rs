@@ -0,0 +1,86 @@
+use std::ffi::CStr;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read};
+use std::mem::MaybeUninit;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+
+/// The emulator's end of a pseudo-terminal.
+///
+/// A handle to the slave end is kept open for as long as the master is
+/// alive, as otherwise reads from the master fail (with EIO) whenever no
+/// terminal program is attached.
+pub struct PtyMaster {
+    master: File,
+    _slave: File,
+}
+
+impl PtyMaster {
+    /// Get a writable handle to the master end of the pty.
+    pub fn try_clone_writer(&self) -> io::Result<File> {
+        self.master.try_clone()
+    }
+}
+
+impl Read for PtyMaster {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.master.read(buf)
+    }
+}
+
+impl AsRawFd for PtyMaster {
+    fn as_raw_fd(&self) -> RawFd {
+        self.master.as_raw_fd()
+    }
+}
+
+/// Allocate a new pseudo-terminal, returning the master end, and the path of
+/// the slave device (e.g: `/dev/pts/3`).
+///
+/// The slave is put into raw mode, so that data passes through untouched
+/// (just like a real serial port).
+pub fn open_pty() -> io::Result<(PtyMaster, String)> {
+    // SAFETY: all pointers passed to libc are valid for the duration of each
+    // call, and the master fd is owned by `master` as soon as it is created.
+    unsafe {
+        let fd = libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY);
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let master = File::from_raw_fd(fd);
+
+        if libc::grantpt(fd) != 0 || libc::unlockpt(fd) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let name = libc::ptsname(fd);
+        if name.is_null() {
+            return Err(io::Error::last_os_error());
+        }
+        let path = CStr::from_ptr(name).to_string_lossy().into_owned();
+
+        let slave = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NOCTTY)
+            .open(&path)?;
+
+        let mut termios = MaybeUninit::<libc::termios>::uninit();
+        if libc::tcgetattr(slave.as_raw_fd(), termios.as_mut_ptr()) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut termios = termios.assume_init();
+        libc::cfmakeraw(&mut termios);
+        if libc::tcsetattr(slave.as_raw_fd(), libc::TCSANOW, &termios) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok((
+            PtyMaster {
+                master,
+                _slave: slave,
+            },
+            path,
+        ))
+    }
+}
@@ -0,0 +1,532 @@
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crossbeam_channel::{self as chan, select};
+
+use super::{HostCmd, ReaderTask};
+use crate::devices::uart::PeerHandle;
+
+/// How long `expect` waits for a match when no timeout is specified
+const DEFAULT_EXPECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A single command in a UART script
+#[derive(Debug, PartialEq)]
+enum Cmd {
+    /// Wait until the pattern shows up in the UART's output, failing if it
+    /// doesn't show up in time.
+    Expect(Vec<u8>, Duration),
+    /// Fail unless the pattern has already shown up in the UART's output.
+    Assert(Vec<u8>),
+    /// Send data into the UART, optionally flagging each character with a
+    /// line error.
+    Send(Vec<u8>, Option<LineError>),
+    /// Send a break into the UART.
+    Break,
+    /// Wait for the specified amount of time.
+    Sleep(Duration),
+    /// Terminate the emulator with the specified exit code.
+    Exit(i32),
+}
+
+/// Line errors which can be injected by a script
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum LineError {
+    Framing,
+    Parity,
+}
+
+/// An expect-style script which drives a UART.
+///
+/// Scripts consist of one command per line (blank lines and lines starting
+/// with `#` are ignored):
+///
+/// - `expect <str> [timeout_ms]`
+/// - `assert <str>`
+/// - `send <str> [framing|parity]`
+/// - `break`
+/// - `sleep <ms>`
+/// - `exit [code]`
+///
+/// Strings may be wrapped in double quotes, and support the `\n`, `\r`, `\t`,
+/// `\\`, `\"`, and `\xNN` escapes.
+///
+/// Injecting line errors (i.e: `send` with an error, or `break`) is only
+/// possible when the script is the UART's primary backend.
+///
+/// Times (i.e: `sleep` and `expect` timeouts) are measured using the host's
+/// clock, which also drives the guest's timers, so `sleep 100` lasts as long
+/// as 100 ms does for the guest. Note that a slow host executes fewer guest
+/// instructions in that time, so timeouts should leave some headroom.
+#[derive(Debug)]
+pub struct Script {
+    // (line number, command)
+    cmds: Vec<(usize, Cmd)>,
+}
+
+impl Script {
+    /// Parse a script, returning a descriptive error on failure.
+    pub fn parse(src: &str) -> Result<Script, String> {
+        let mut cmds = Vec::new();
+        for (i, line) in src.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let cmd = parse_cmd(line).map_err(|e| format!("line {}: {}", i + 1, e))?;
+            cmds.push((i + 1, cmd));
+        }
+        Ok(Script { cmds })
+    }
+}
+
+fn parse_cmd(line: &str) -> Result<Cmd, String> {
+    let (cmd, args) = match line.find(char::is_whitespace) {
+        Some(idx) => (&line[..idx], line[idx..].trim_start()),
+        None => (line, ""),
+    };
+
+    let parse_ms = |s: &str| -> Result<Duration, String> {
+        s.parse()
+            .map(Duration::from_millis)
+            .map_err(|_| format!("invalid duration {:?} (expected ms)", s))
+    };
+
+    Ok(match cmd {
+        "expect" => {
+            let (pattern, rest) = parse_str(args)?;
+            let timeout = match rest.trim() {
+                "" => DEFAULT_EXPECT_TIMEOUT,
+                ms => parse_ms(ms)?,
+            };
+            Cmd::Expect(pattern, timeout)
+        }
+        "assert" => {
+            let (s, rest) = parse_str(args)?;
+            if !rest.trim().is_empty() {
+                return Err(format!("unexpected trailing {:?}", rest.trim()));
+            }
+            Cmd::Assert(s)
+        }
+        "send" => {
+            let (s, rest) = parse_str(args)?;
+            let error = match rest.trim() {
+                "" => None,
+                "framing" => Some(LineError::Framing),
+                "parity" => Some(LineError::Parity),
+                other => return Err(format!("invalid line error {:?}", other)),
+            };
+            Cmd::Send(s, error)
+        }
+        "break" if args.is_empty() => Cmd::Break,
+        "sleep" => Cmd::Sleep(parse_ms(args)?),
+        "exit" => Cmd::Exit(match args {
+            "" => 0,
+            code => code
+                .parse()
+                .map_err(|_| format!("invalid exit code {:?}", code))?,
+        }),
+        _ => return Err(format!("unknown command {:?}", cmd)),
+    })
+}
+
+/// Parse a (possibly quoted) string, returning it alongside the rest of the
+/// line.
+fn parse_str(s: &str) -> Result<(Vec<u8>, &str), String> {
+    if !s.starts_with('"') {
+        return match s.find(char::is_whitespace) {
+            Some(idx) if idx > 0 => Ok((unescape(&s[..idx])?, &s[idx..])),
+            _ if !s.is_empty() => Ok((unescape(s)?, "")),
+            _ => Err("expected a string".to_string()),
+        };
+    }
+
+    let mut escaped = false;
+    for (i, c) in s.char_indices().skip(1) {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '"' => return Ok((unescape(&s[1..i])?, &s[i + 1..])),
+            _ => {}
+        }
+    }
+    Err("unterminated string".to_string())
+}
+
+fn unescape(s: &str) -> Result<Vec<u8>, String> {
+    let mut out = Vec::new();
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            let mut buf = [0; 4];
+            out.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+            continue;
+        }
+
+        match chars.next() {
+            Some('n') => out.push(b'\n'),
+            Some('r') => out.push(b'\r'),
+            Some('t') => out.push(b'\t'),
+            Some('\\') => out.push(b'\\'),
+            Some('"') => out.push(b'"'),
+            Some('x') => {
+                let hex: String = chars.by_ref().take(2).collect();
+                let b = u8::from_str_radix(&hex, 16)
+                    .map_err(|_| format!("invalid escape \\x{}", hex))?;
+                out.push(b);
+            }
+            Some(c) => return Err(format!("invalid escape \\{}", c)),
+            None => return Err("trailing backslash".to_string()),
+        }
+    }
+    Ok(out)
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    if needle.is_empty() {
+        return Some(0);
+    }
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+/// How a script stopped running
+#[derive(Debug, PartialEq)]
+enum Outcome {
+    /// Ran to completion
+    Done,
+    /// Asked to exit the emulator with the specified code
+    Exit(i32),
+    /// Cancelled, or the UART hung up
+    Detached,
+}
+
+/// Everything a running script needs to interact with its UART
+struct Ctx {
+    label: String,
+    peer: Option<PeerHandle>,
+    tx: chan::Sender<u8>,
+    rx: chan::Receiver<u8>,
+    exit_rx: chan::Receiver<()>,
+    // output received since the last successful match
+    output: Vec<u8>,
+}
+
+impl Ctx {
+    fn fail(&self, line: usize, msg: &str) -> Outcome {
+        eprintln!(
+            "{}: line {}: {} (output: {:?})",
+            self.label,
+            line,
+            msg,
+            String::from_utf8_lossy(&self.output)
+        );
+        Outcome::Exit(1)
+    }
+
+    /// Wait until `deadline`, or until the script is cancelled. Returns early
+    /// if any output is received, which is buffered.
+    fn wait(&mut self, deadline: Instant) -> Result<(), Outcome> {
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        select! {
+            recv(self.rx) -> b => match b {
+                Ok(b) => self.output.push(b),
+                Err(chan::RecvError) => return Err(Outcome::Detached),
+            },
+            recv(self.exit_rx) -> _ => return Err(Outcome::Detached),
+            default(timeout) => {},
+        }
+        Ok(())
+    }
+
+    fn run_cmd(&mut self, line: usize, cmd: Cmd) -> Result<(), Outcome> {
+        match cmd {
+            Cmd::Expect(pattern, timeout) => {
+                let deadline = Instant::now() + timeout;
+                loop {
+                    if let Some(pos) = find(&self.output, &pattern) {
+                        self.output.drain(..pos + pattern.len());
+                        return Ok(());
+                    }
+
+                    if Instant::now() >= deadline {
+                        let msg = format!(
+                            "timed out waiting for {:?}",
+                            String::from_utf8_lossy(&pattern)
+                        );
+                        return Err(self.fail(line, &msg));
+                    }
+                    self.wait(deadline)?;
+                }
+            }
+            Cmd::Assert(pattern) => {
+                self.output.extend(self.rx.try_iter());
+                match find(&self.output, &pattern) {
+                    Some(pos) => drop(self.output.drain(..pos + pattern.len())),
+                    None => {
+                        let msg = format!(
+                            "assertion failed: expected {:?}",
+                            String::from_utf8_lossy(&pattern)
+                        );
+                        return Err(self.fail(line, &msg));
+                    }
+                }
+            }
+            Cmd::Send(data, error) => match &self.peer {
+                Some(peer) => {
+                    let framing = error == Some(LineError::Framing);
+                    let parity = error == Some(LineError::Parity);
+                    for b in data {
+                        peer.send_with_errors(b, framing, parity);
+                    }
+                }
+                None if error.is_some() => {
+                    let msg = "line errors can only be injected by the primary backend";
+                    return Err(self.fail(line, msg));
+                }
+                None => {
+                    for b in data {
+                        if self.tx.send(b).is_err() {
+                            return Err(Outcome::Detached);
+                        }
+                    }
+                }
+            },
+            Cmd::Break => match &self.peer {
+                Some(peer) => peer.send_break(),
+                None => {
+                    let msg = "breaks can only be sent by the primary backend";
+                    return Err(self.fail(line, msg));
+                }
+            },
+            Cmd::Sleep(duration) => {
+                let deadline = Instant::now() + duration;
+                while Instant::now() < deadline {
+                    self.wait(deadline)?;
+                }
+            }
+            Cmd::Exit(code) => {
+                eprintln!("{}: script exited with code {}", self.label, code);
+                return Err(Outcome::Exit(code));
+            }
+        }
+        Ok(())
+    }
+
+    fn run(&mut self, script: Script) -> Outcome {
+        for (line, cmd) in script.cmds {
+            if let Err(outcome) = self.run_cmd(line, cmd) {
+                return outcome;
+            }
+        }
+        Outcome::Done
+    }
+}
+
+/// Spawn a thread which runs `script` against a UART.
+///
+/// Failed expectations / assertions ask the system to exit with a non-zero
+/// exit code (via `host_cmds`). Once the script finishes, any further output
+/// is discarded.
+///
+/// If provided, all data is sent via `peer` (instead of `tx`), which is
+/// required to inject line errors.
+pub fn script_to_chans(
+    thread_label: String,
+    script: Script,
+    peer: Option<PeerHandle>,
+    host_cmds: chan::Sender<HostCmd>,
+    tx: chan::Sender<u8>,
+    rx: chan::Receiver<u8>,
+) -> ReaderTask {
+    let (exit_tx, exit_rx) = chan::bounded(1);
+
+    let mut ctx = Ctx {
+        label: thread_label.clone(),
+        peer,
+        tx,
+        rx,
+        exit_rx,
+        output: Vec::new(),
+    };
+    let thread = move || {
+        match ctx.run(script) {
+            Outcome::Done => {}
+            Outcome::Exit(code) => {
+                // the system tears down the UARTs (and with them, this
+                // thread) before exiting
+                let _ = host_cmds.send(HostCmd::Exit(code));
+            }
+            Outcome::Detached => return,
+        }
+
+        loop {
+            select! {
+                recv(ctx.rx) -> b => if b.is_err() {
+                    return;
+                },
+                recv(ctx.exit_rx) -> _ => return,
+            }
+        }
+    };
+
+    let handle = thread::Builder::new()
+        .name(format!("{} - Script", thread_label))
+        .spawn(thread)
+        .expect("failed to spawn thread");
+
+    ReaderTask::new(handle, exit_tx)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let script = Script::parse(
+            r#"
+            # comment
+            expect "login: "
+            send root\n
+              expect $ 250
+            send "\x03" framing
+            assert "bye\"\\"
+            break
+            sleep 10
+            exit 3
+            "#,
+        )
+        .unwrap();
+
+        let cmds: Vec<_> = script.cmds.iter().map(|(line, _)| *line).collect();
+        assert_eq!(cmds, [3, 4, 5, 6, 7, 8, 9, 10]);
+        let cmds: Vec<_> = script.cmds.into_iter().map(|(_, cmd)| cmd).collect();
+        assert_eq!(
+            cmds,
+            [
+                Cmd::Expect(b"login: ".to_vec(), DEFAULT_EXPECT_TIMEOUT),
+                Cmd::Send(b"root\n".to_vec(), None),
+                Cmd::Expect(b"$".to_vec(), Duration::from_millis(250)),
+                Cmd::Send(b"\x03".to_vec(), Some(LineError::Framing)),
+                Cmd::Assert(b"bye\"\\".to_vec()),
+                Cmd::Break,
+                Cmd::Sleep(Duration::from_millis(10)),
+                Cmd::Exit(3),
+            ]
+        );
+
+        for bad in &[
+            "expect",
+            "expect \"unterminated",
+            "expect foo 1s",
+            "assert foo bar",
+            "send foo overrun",
+            "send \\q",
+            "send \\xzz",
+            "break now",
+            "sleep",
+            "exit code",
+            "launch",
+        ] {
+            assert!(Script::parse(bad).is_err(), "{:?}", bad);
+        }
+        assert_eq!(
+            Script::parse("\n\nsleep -1").unwrap_err(),
+            "line 3: invalid duration \"-1\" (expected ms)"
+        );
+    }
+
+    /// Returns a `Ctx` without a peer, alongside the UART's ends of its
+    /// channels (i.e: script input, script output, and the exit signal).
+    fn ctx() -> (Ctx, chan::Sender<u8>, chan::Receiver<u8>, chan::Sender<()>) {
+        let (uart_tx, rx) = chan::unbounded();
+        let (tx, uart_rx) = chan::unbounded();
+        let (exit_tx, exit_rx) = chan::bounded(1);
+        let ctx = Ctx {
+            label: "test".into(),
+            peer: None,
+            tx,
+            rx,
+            exit_rx,
+            output: Vec::new(),
+        };
+        (ctx, uart_tx, uart_rx, exit_tx)
+    }
+
+    fn send(tx: &chan::Sender<u8>, s: &[u8]) {
+        for &b in s {
+            tx.send(b).unwrap();
+        }
+    }
+
+    #[test]
+    fn run_expect_assert() {
+        let (mut ctx, uart_tx, _uart_rx, _exit_tx) = ctx();
+        let timeout = Duration::from_millis(100);
+
+        send(&uart_tx, b"login: root\n$ ");
+        assert_eq!(
+            ctx.run_cmd(1, Cmd::Expect(b"login:".to_vec(), timeout)),
+            Ok(())
+        );
+        // expect stops reading as soon as the pattern shows up, and matches
+        // consume all output up to (and including) the pattern
+        assert!(ctx.output.is_empty());
+        assert_eq!(ctx.run_cmd(2, Cmd::Assert(b"$".to_vec())), Ok(()));
+        assert_eq!(ctx.output, b" ");
+        assert_eq!(
+            ctx.run_cmd(3, Cmd::Assert(b"login".to_vec())),
+            Err(Outcome::Exit(1))
+        );
+
+        let timeout = Duration::from_millis(10);
+        assert_eq!(
+            ctx.run_cmd(4, Cmd::Expect(b"#".to_vec(), timeout)),
+            Err(Outcome::Exit(1))
+        );
+    }
+
+    #[test]
+    fn run_send() {
+        let (mut ctx, _uart_tx, uart_rx, _exit_tx) = ctx();
+
+        assert_eq!(ctx.run_cmd(1, Cmd::Send(b"ls\n".to_vec(), None)), Ok(()));
+        assert_eq!(uart_rx.try_iter().collect::<Vec<_>>(), b"ls\n");
+
+        // line errors require a peer
+        let cmd = Cmd::Send(b"x".to_vec(), Some(LineError::Parity));
+        assert_eq!(ctx.run_cmd(2, cmd), Err(Outcome::Exit(1)));
+        assert_eq!(ctx.run_cmd(3, Cmd::Break), Err(Outcome::Exit(1)));
+        assert!(uart_rx.is_empty());
+
+        drop(uart_rx);
+        assert_eq!(
+            ctx.run_cmd(4, Cmd::Send(b"x".to_vec(), None)),
+            Err(Outcome::Detached)
+        );
+    }
+
+    #[test]
+    fn run_sleep_exit() {
+        let (mut ctx, uart_tx, _uart_rx, exit_tx) = ctx();
+
+        // output received while sleeping is buffered
+        send(&uart_tx, b"ok");
+        let start = Instant::now();
+        assert_eq!(
+            ctx.run_cmd(1, Cmd::Sleep(Duration::from_millis(20))),
+            Ok(())
+        );
+        assert!(start.elapsed() >= Duration::from_millis(20));
+        assert_eq!(ctx.output, b"ok");
+
+        assert_eq!(ctx.run_cmd(2, Cmd::Exit(7)), Err(Outcome::Exit(7)));
+
+        exit_tx.send(()).unwrap();
+        assert_eq!(
+            ctx.run_cmd(3, Cmd::Sleep(Duration::from_secs(60))),
+            Err(Outcome::Detached)
+        );
+    }
+}
@@ -0,0 +1,222 @@
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::thread;
+
+use crossbeam_channel::{self as chan, select};
+
+use super::{wait_readable, wait_writable, ReaderTask, WriterTask};
+
+/// Common interface over the various kinds of socket listeners.
+pub trait Listener: AsRawFd + Send + 'static {
+    type Stream: Read + Write + AsRawFd + Send + 'static;
+
+    /// Accept a new connection, returning the stream and a description of the
+    /// client.
+    fn accept_client(&self) -> io::Result<(Self::Stream, String)>;
+
+    /// Get another handle to the same underlying stream.
+    fn clone_stream(stream: &Self::Stream) -> io::Result<Self::Stream>;
+}
+
+impl Listener for TcpListener {
+    type Stream = TcpStream;
+
+    fn accept_client(&self) -> io::Result<(TcpStream, String)> {
+        let (stream, addr) = self.accept()?;
+        Ok((stream, addr.to_string()))
+    }
+
+    fn clone_stream(stream: &TcpStream) -> io::Result<TcpStream> {
+        stream.try_clone()
+    }
+}
+
+/// A [UnixListener] which removes its socket file once it's dropped.
+pub struct UnixSocketListener {
+    listener: UnixListener,
+    path: PathBuf,
+}
+
+impl UnixSocketListener {
+    /// Bind a new socket at `path`.
+    ///
+    /// Any stale socket left behind by a previous run is replaced, but binding
+    /// fails with `AddrInUse` if something is still listening on it.
+    pub fn bind(path: impl Into<PathBuf>) -> io::Result<UnixSocketListener> {
+        let path = path.into();
+        if let Ok(meta) = fs::metadata(&path) {
+            if meta.file_type().is_socket() {
+                if UnixStream::connect(&path).is_ok() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::AddrInUse,
+                        "socket is in use by another process",
+                    ));
+                }
+                fs::remove_file(&path)?;
+            }
+        }
+
+        let listener = UnixListener::bind(&path)?;
+        Ok(UnixSocketListener { listener, path })
+    }
+}
+
+impl Drop for UnixSocketListener {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+impl AsRawFd for UnixSocketListener {
+    fn as_raw_fd(&self) -> RawFd {
+        self.listener.as_raw_fd()
+    }
+}
+
+impl Listener for UnixSocketListener {
+    type Stream = UnixStream;
+
+    fn accept_client(&self) -> io::Result<(UnixStream, String)> {
+        // unix socket clients are almost always unnamed
+        let (stream, _) = self.listener.accept()?;
+        Ok((stream, "unix socket".to_string()))
+    }
+
+    fn clone_stream(stream: &UnixStream) -> io::Result<UnixStream> {
+        stream.try_clone()
+    }
+}
+
+/// Forward data from a client's stream to `tx` until it disconnects. Returns
+/// `false` if the reader was cancelled (or the UART hung up).
+fn serve_client(
+    mut stream: impl Read + AsRawFd,
+    tx: &chan::Sender<u8>,
+    exit: &chan::Receiver<()>,
+) -> bool {
+    let mut buf = [0; 256];
+    while wait_readable(stream.as_raw_fd(), exit) {
+        let n = match stream.read(&mut buf) {
+            Ok(0) => return true,
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            // e.g: connection reset by peer
+            Err(_) => return true,
+        };
+        for &b in &buf[..n] {
+            if tx.send(b).is_err() {
+                return false;
+            }
+        }
+    }
+    false
+}
+
+/// Accept connections on `listener` (one at a time), connecting each client
+/// to the tx/rx channels. Once a client disconnects, the next connection is
+/// accepted.
+///
+/// `initial` is used as the first client (if provided), and `on_connect` is
+/// invoked whenever a client connects / disconnects. Data sent while no client
+/// is connected is discarded.
+pub fn server_to_chans<L: Listener>(
+    thread_label: String,
+    listener: L,
+    initial: Option<L::Stream>,
+    on_connect: impl Fn(bool) + Send + 'static,
+    tx: chan::Sender<u8>,
+    rx: chan::Receiver<u8>,
+) -> (ReaderTask, WriterTask) {
+    // new clients are handed off from the reader thread to the writer thread
+    let (client_tx, client_rx) = chan::unbounded::<Option<L::Stream>>();
+
+    let (reader_exit_tx, reader_exit_rx) = chan::bounded(1);
+    let reader_label = thread_label.clone();
+    let reader = move || {
+        let mut next_client = initial;
+        loop {
+            let stream = match next_client.take() {
+                Some(stream) => stream,
+                None => {
+                    if !wait_readable(listener.as_raw_fd(), &reader_exit_rx) {
+                        return;
+                    }
+                    match listener.accept_client() {
+                        Ok((stream, client)) => {
+                            info!("{} - client connected from {}", reader_label, client);
+                            stream
+                        }
+                        Err(e) => {
+                            warn!("{} - could not accept connection: {}", reader_label, e);
+                            continue;
+                        }
+                    }
+                }
+            };
+
+            let out_stream = L::clone_stream(&stream).expect("could not clone stream");
+            if client_tx.send(Some(out_stream)).is_err() {
+                return;
+            }
+            on_connect(true);
+
+            let keep_going = serve_client(stream, &tx, &reader_exit_rx);
+
+            on_connect(false);
+            let _ = client_tx.send(None);
+            if !keep_going {
+                return;
+            }
+            info!("{} - client disconnected", reader_label);
+        }
+    };
+
+    let (writer_exit_tx, writer_exit_rx) = chan::bounded(1);
+    let writer = move || {
+        let mut client: Option<L::Stream> = None;
+        loop {
+            select! {
+                recv(client_rx) -> new_client => match new_client {
+                    Ok(new_client) => client = new_client,
+                    // reader thread exited
+                    Err(chan::RecvError) => return,
+                },
+                recv(rx) -> b => match b {
+                    Ok(b) => {
+                        if let Some(stream) = &mut client {
+                            // a client which stops reading mustn't prevent the
+                            // writer from being cancelled
+                            if !wait_writable(stream.as_raw_fd(), &writer_exit_rx) {
+                                return;
+                            }
+                            if stream.write_all(&[b]).is_err() {
+                                client = None;
+                            }
+                        }
+                    }
+                    Err(chan::RecvError) => return,
+                },
+                recv(writer_exit_rx) -> _ => return,
+            }
+        }
+    };
+
+    let reader_handle = thread::Builder::new()
+        .name(format!("{} - Server Reader", thread_label))
+        .spawn(reader)
+        .expect("failed to spawn thread");
+    let writer_handle = thread::Builder::new()
+        .name(format!("{} - Server Writer", thread_label))
+        .spawn(writer)
+        .expect("failed to spawn thread");
+
+    (
+        ReaderTask::new(reader_handle, reader_exit_tx),
+        WriterTask::new(writer_handle, writer_exit_tx),
+    )
+}
@@ -1,42 +1,169 @@
+use std::fs::File;
 use std::io::{self, Read, Write};
-use std::thread::{self, JoinHandle};
+use std::mem::ManuallyDrop;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::thread;
 
 use crossbeam_channel::{self as chan, select};
+use log::LevelFilter;
 use termion::raw::IntoRawMode;
 
-struct CtrlC;
+use super::{wait_readable, Keymap, ReaderTask, WriterTask};
+use crate::devices::uart::StdioCfg;
+
+/// Commands entered via the stdio leader key which must be handled by the
+/// system itself.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HostCmd {
+    /// Break into the GDB session
+    Break,
+    /// Dump the system's state to a file
+    Snapshot,
+    /// Reset the system
+    Reset,
+    /// Stop the system, and exit the emulator with the specified code
+    Exit(i32),
+}
 
-fn spawn_reader_thread(tx: chan::Sender<u8>, ctrl_c_exit: chan::Sender<CtrlC>) -> JoinHandle<()> {
-    let thread = move || {
-        for b in io::stdin().bytes() {
-            let b = b.unwrap();
-            if b == 3 {
-                // ctrl-c
-                eprintln!("Recieved Ctrl-c - terminating now...");
-                ctrl_c_exit.send(CtrlC).unwrap();
+struct Quit;
+
+/// Stop the emulator. If possible, this is left to the system, so that the
+/// UART backends (and transcript) get cleaned up properly.
+fn quit(cfg: &StdioCfg, quit: &chan::Sender<Quit>) {
+    match &cfg.host_cmds {
+        Some(host_cmds) => {
+            let _ = host_cmds.send(HostCmd::Exit(1));
+        }
+        // the writer thread may have already exited
+        None => {
+            let _ = quit.send(Quit);
+        }
+    }
+}
+
+/// Handle the key following the leader key. Any keys which should be sent to
+/// the UART are appended to `out`.
+fn leader_cmd(
+    key: u8,
+    cfg: &StdioCfg,
+    out: &mut Vec<u8>,
+    quit: &chan::Sender<Quit>,
+    saved_log_level: &mut LevelFilter,
+) {
+    let host_cmd = match key {
+        b'x' => {
+            eprintln!("Quitting...");
+            return self::quit(cfg, quit);
+        }
+        b'c' => return out.push(3),
+        b'l' => {
+            if log::max_level() == LevelFilter::Off {
+                log::set_max_level(*saved_log_level);
+                eprintln!("Logging enabled");
+            } else {
+                *saved_log_level = log::max_level();
+                log::set_max_level(LevelFilter::Off);
+                eprintln!("Logging disabled");
             }
-            // Key code remapping to match gtkterm.
-            let b = match b {
-                127 => 8,
-                _ => b,
+            return;
+        }
+        b'b' => HostCmd::Break,
+        b's' => HostCmd::Snapshot,
+        b'r' => HostCmd::Reset,
+        // pressing the leader twice sends it through as-is
+        _ if Some(key) == cfg.leader => return out.push(key),
+        _ => {
+            eprintln!(
+                "Unknown command {:?} (x: quit, c: send Ctrl-C, b: break into GDB, s: \
+                 snapshot, r: reset, l: toggle logging)",
+                key as char
+            );
+            return;
+        }
+    };
+
+    match &cfg.host_cmds {
+        Some(host_cmds) => host_cmds.send(host_cmd).unwrap(),
+        None => eprintln!("{:?} isn't supported here", host_cmd),
+    }
+}
+
+fn spawn_reader_thread(
+    tx: chan::Sender<u8>,
+    quit: chan::Sender<Quit>,
+    cfg: StdioCfg,
+) -> ReaderTask {
+    let (exit_tx, exit_rx) = chan::bounded(1);
+
+    let thread = move || {
+        let mut after_leader = false;
+        let mut saved_log_level = log::max_level();
+        let mut input = cfg.keymap.input_mapper();
+        let mut mapped = Vec::new();
+
+        // Read directly from the underlying fd, as data sitting in Stdin's
+        // internal buffer wouldn't be picked up by `wait_readable`.
+        // SAFETY: stdin remains open for the lifetime of the process, and
+        // ManuallyDrop ensures it isn't closed once the reader exits.
+        let mut stdin = ManuallyDrop::new(unsafe { File::from_raw_fd(io::stdin().as_raw_fd()) });
+
+        let mut buf = [0; 256];
+        while wait_readable(stdin.as_raw_fd(), &exit_rx) {
+            let n = match stdin.read(&mut buf) {
+                Ok(0) => return,
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    warn!("stdio - read failed ({}), disconnecting", e);
+                    return;
+                }
             };
 
-            match tx.send(b) {
-                Ok(()) => {}
-                Err(chan::SendError(_)) => return,
+            for &b in &buf[..n] {
+                if after_leader {
+                    after_leader = false;
+                    // keep any pending escape sequence ahead of whatever the
+                    // command sends
+                    input.flush(&mut mapped);
+                    leader_cmd(b, &cfg, &mut mapped, &quit, &mut saved_log_level);
+                    continue;
+                }
+                if Some(b) == cfg.leader {
+                    after_leader = true;
+                    continue;
+                }
+
+                // Without a leader key, ctrl-c is the only way to quit
+                if b == 3 && cfg.leader.is_none() {
+                    eprintln!("Recieved Ctrl-c - terminating now...");
+                    self::quit(&cfg, &quit);
+                }
+
+                input.push(b, &mut mapped);
+            }
+            input.flush(&mut mapped);
+
+            for b in mapped.drain(..) {
+                match tx.send(b) {
+                    Ok(()) => {}
+                    Err(chan::SendError(_)) => return,
+                }
             }
         }
     };
 
-    thread::Builder::new()
+    let handle = thread::Builder::new()
         .name("stdio reader".to_string())
         .spawn(thread)
-        .expect("failed to spawn thread")
+        .expect("failed to spawn thread");
+
+    ReaderTask::new(handle, exit_tx)
 }
 
-fn spawn_writer_thread(rx: chan::Receiver<u8>) -> (JoinHandle<()>, chan::Sender<CtrlC>) {
-    let (ctrl_c_exit_tx, ctrl_c_exit_rx) = chan::bounded::<CtrlC>(1);
+fn spawn_writer_thread(rx: chan::Receiver<u8>, keymap: Keymap) -> (WriterTask, chan::Sender<Quit>) {
+    let (quit_tx, mut quit_rx) = chan::bounded::<Quit>(1);
     let (ready_tx, ready_rx) = chan::unbounded::<()>();
+    let (exit_tx, exit_rx) = chan::bounded(1);
 
     let thread = move || {
         let mut stdout = io::stdout();
@@ -53,21 +180,25 @@ fn spawn_writer_thread(rx: chan::Receiver<u8>) -> (JoinHandle<()>, chan::Sender<
 
         ready_tx.send(()).unwrap();
 
+        let mut mapped = Vec::new();
+
         loop {
             select! {
                 recv(rx) -> b => {
                     match b {
                         Ok(b) => {
-                            stdout.write_all(&[b]).expect("io error");
+                            keymap.map_output(b, &mut mapped);
+                            stdout.write_all(&mapped).expect("io error");
                             stdout.flush().expect("io error");
+                            mapped.clear();
                         }
                         Err(chan::RecvError) => break,
                     }
 
                 }
-                recv(ctrl_c_exit_rx) -> exit => {
+                recv(quit_rx) -> exit => {
                     match exit {
-                        Ok(CtrlC) => {
+                        Ok(Quit) => {
                             if let Some(handle) = raw_mode_handle {
                                 handle.suspend_raw_mode().unwrap();
                             }
@@ -75,24 +206,20 @@ fn spawn_writer_thread(rx: chan::Receiver<u8>) -> (JoinHandle<()>, chan::Sender<
                         }
                         Err(chan::RecvError) => {
                             // The reader thread has shut down without sending
-                            // a CtrlC message. This typically happens when
+                            // a Quit message. This typically happens when
                             // piping data in via stdout, and the sender process
-                            // shuts down.
+                            // shuts down (or when the reader is cancelled).
                             // The writer thread should stay alive though, and
                             // keep processing outgoing data.
                             //
-                            // NOTE: we clone this logic here as select! will
-                            // continously hammer this branch, even though the
-                            // channel has already closed
-                            for b in rx {
-                                stdout.write_all(&[b]).expect("io error");
-                                stdout.flush().expect("io error");
-                            }
-                            break;
+                            // NOTE: select! would continuously hammer this
+                            // branch if the closed channel was left in place
+                            quit_rx = chan::never();
                         }
                     }
 
                 }
+                recv(exit_rx) -> _ => break,
             }
         }
         if let Some(handle) = raw_mode_handle {
@@ -107,16 +234,17 @@ fn spawn_writer_thread(rx: chan::Receiver<u8>) -> (JoinHandle<()>, chan::Sender<
 
     ready_rx.recv().unwrap();
 
-    (handle, ctrl_c_exit_tx)
+    (WriterTask::new(handle, exit_tx), quit_tx)
 }
 
 /// Put Stdin into raw mode, and connect Stdin/Stdout to the tx/rx channels.
 pub fn stdio_to_chans(
+    cfg: StdioCfg,
     tx: chan::Sender<u8>,
     rx: chan::Receiver<u8>,
-) -> (JoinHandle<()>, JoinHandle<()>) {
+) -> (ReaderTask, WriterTask) {
     // the writer thread MUST be spawned first, as it sets the raw term mode
-    let (writer_handle, ctrl_c_exit) = spawn_writer_thread(rx);
-    let reader_handle = spawn_reader_thread(tx, ctrl_c_exit);
-    (reader_handle, writer_handle)
+    let (writer_task, quit) = spawn_writer_thread(rx, cfg.keymap);
+    let reader_task = spawn_reader_thread(tx, quit, cfg);
+    (reader_task, writer_task)
 }
@@ -1,7 +1,10 @@
 mod cfg;
 mod device;
+mod transcript;
 
 pub use cfg::*;
 pub use device::*;
+pub use transcript::*;
 
 mod iothreads;
+pub use iothreads::{HostCmd, Keymap};
@@ -0,0 +1,285 @@
+//! Timestamped capture of all the traffic crossing the system's UARTs.
+//!
+//! Transcripts use a compact binary format: an 8 byte magic + u32 version
+//! header, followed by fixed size little-endian records:
+//!
+//! | offset | size | field                                     |
+//! |--------|------|-------------------------------------------|
+//! | 0      | 8    | host time since the capture started (ns)  |
+//! | 8      | 8    | instructions executed by the CPU          |
+//! | 16     | 1    | UART number                               |
+//! | 17     | 1    | event kind (see [TranscriptEvent])        |
+//! | 18     | 1    | data byte / line level                    |
+//! | 19     | 1    | flags                                     |
+//! | 20     | 4    | reserved                                  |
+
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use byteorder::{ByteOrder, LittleEndian};
+
+const MAGIC: &[u8; 8] = b"TS72UART";
+const VERSION: u32 = 1;
+const RECORD_LEN: usize = 24;
+
+// Rx flags (matching bits 8-11 of the Data register)
+const FLAG_FE: u8 = 0x1;
+const FLAG_PE: u8 = 0x2;
+const FLAG_BE: u8 = 0x4;
+const FLAG_OE: u8 = 0x8;
+
+// Tx flags
+const FLAG_DROPPED: u8 = 0x1;
+
+/// Events captured in a UART transcript.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TranscriptEvent {
+    /// A byte was received from the peer, alongside any receive error bits
+    /// (in the RXSts register format).
+    Rx { byte: u8, errors: u8 },
+    /// A byte was shifted out onto the wire. `dropped` is set if the peer
+    /// ignored it (e.g: it was sent while CTS was deasserted).
+    Tx { byte: u8, dropped: bool },
+    /// The CTS line changed level.
+    Cts(bool),
+    /// The guest started / stopped sending a break.
+    Break(bool),
+}
+
+impl TranscriptEvent {
+    fn encode(self) -> (u8, u8, u8) {
+        match self {
+            TranscriptEvent::Rx { byte, errors } => (0, byte, errors),
+            TranscriptEvent::Tx { byte, dropped } => (1, byte, dropped as u8),
+            TranscriptEvent::Cts(level) => (2, level as u8, 0),
+            TranscriptEvent::Break(on) => (3, on as u8, 0),
+        }
+    }
+
+    fn decode(kind: u8, data: u8, flags: u8) -> Option<TranscriptEvent> {
+        Some(match kind {
+            0 => TranscriptEvent::Rx {
+                byte: data,
+                errors: flags,
+            },
+            1 => TranscriptEvent::Tx {
+                byte: data,
+                dropped: flags & FLAG_DROPPED != 0,
+            },
+            2 => TranscriptEvent::Cts(data != 0),
+            3 => TranscriptEvent::Break(data != 0),
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Debug)]
+struct Inner {
+    // flushed when the last handle is dropped, or explicitly via
+    // `Transcript::flush`
+    file: Mutex<BufWriter<File>>,
+    start: Instant,
+    insn_count: Arc<AtomicU64>,
+}
+
+/// A transcript file shared between several UARTs.
+#[derive(Debug, Clone)]
+pub struct Transcript {
+    inner: Arc<Inner>,
+}
+
+impl Transcript {
+    /// Create a new transcript at `path`, timestamping events with the
+    /// provided instruction counter.
+    pub fn create(path: &str, insn_count: Arc<AtomicU64>) -> io::Result<Transcript> {
+        let mut file = BufWriter::new(File::create(path)?);
+        let mut header = [0; 12];
+        header[..8].copy_from_slice(MAGIC);
+        LittleEndian::write_u32(&mut header[8..], VERSION);
+        file.write_all(&header)?;
+
+        Ok(Transcript {
+            inner: Arc::new(Inner {
+                file: Mutex::new(file),
+                start: Instant::now(),
+                insn_count,
+            }),
+        })
+    }
+
+    /// Write out any buffered events. Must be called before exiting the
+    /// process without unwinding (e.g: via `std::process::exit`).
+    pub fn flush(&self) {
+        if let Err(e) = self.inner.file.lock().unwrap().flush() {
+            warn!("failed to write to UART transcript: {}", e);
+        }
+    }
+
+    /// Return a handle with which the specified UART can record events.
+    pub fn handle(&self, uart: u8) -> TranscriptHandle {
+        TranscriptHandle {
+            transcript: self.clone(),
+            uart,
+        }
+    }
+}
+
+/// Handle used by a single UART to record events to a [Transcript].
+#[derive(Debug, Clone)]
+pub struct TranscriptHandle {
+    transcript: Transcript,
+    uart: u8,
+}
+
+impl TranscriptHandle {
+    /// Append an event to the transcript.
+    pub fn record(&self, event: TranscriptEvent) {
+        let inner = &self.transcript.inner;
+
+        let (kind, data, flags) = event.encode();
+        let mut record = [0; RECORD_LEN];
+        LittleEndian::write_u64(&mut record[0..], inner.start.elapsed().as_nanos() as u64);
+        LittleEndian::write_u64(&mut record[8..], inner.insn_count.load(Ordering::Relaxed));
+        record[16] = self.uart;
+        record[17] = kind;
+        record[18] = data;
+        record[19] = flags;
+
+        if let Err(e) = inner.file.lock().unwrap().write_all(&record) {
+            warn!("failed to write to UART transcript: {}", e);
+        }
+    }
+}
+
+/// Pretty-print a transcript (as captured via `--uart-transcript`).
+pub fn dump_transcript(mut input: impl Read, mut out: impl Write) -> io::Result<()> {
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+
+    let mut header = [0; 12];
+    input.read_exact(&mut header)?;
+    if &header[..8] != MAGIC {
+        return Err(invalid("not a UART transcript"));
+    }
+    if LittleEndian::read_u32(&header[8..]) != VERSION {
+        return Err(invalid("unsupported UART transcript version"));
+    }
+
+    let mut record = [0; RECORD_LEN];
+    loop {
+        match input.read_exact(&mut record) {
+            Ok(()) => {}
+            // a truncated trailing record is expected if the emulator was
+            // killed mid-write
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e),
+        }
+
+        let host_ns = LittleEndian::read_u64(&record[0..]);
+        let insn = LittleEndian::read_u64(&record[8..]);
+        let event = TranscriptEvent::decode(record[17], record[18], record[19])
+            .ok_or_else(|| invalid("invalid UART transcript event"))?;
+
+        write!(
+            out,
+            "{:>4}.{:06}s  insn {:>12}  uart{}  ",
+            host_ns / 1_000_000_000,
+            host_ns / 1000 % 1_000_000,
+            insn,
+            record[16]
+        )?;
+
+        match event {
+            TranscriptEvent::Rx { byte, errors } => {
+                write!(out, "rx  {:#04x}{}", byte, printable(byte))?;
+                for &(flag, name) in &[
+                    (FLAG_FE, "FE"),
+                    (FLAG_PE, "PE"),
+                    (FLAG_BE, "BE"),
+                    (FLAG_OE, "OE"),
+                ] {
+                    if errors & flag != 0 {
+                        write!(out, " {}", name)?;
+                    }
+                }
+                writeln!(out)?;
+            }
+            TranscriptEvent::Tx { byte, dropped } => {
+                write!(out, "tx  {:#04x}{}", byte, printable(byte))?;
+                if dropped {
+                    write!(out, " (dropped by peer)")?;
+                }
+                writeln!(out)?;
+            }
+            TranscriptEvent::Cts(level) => writeln!(out, "cts {}", level as u8)?,
+            TranscriptEvent::Break(true) => writeln!(out, "brk start")?,
+            TranscriptEvent::Break(false) => writeln!(out, "brk end")?,
+        }
+    }
+}
+
+/// Render a byte as a (space-prefixed) quoted character, if it's printable.
+fn printable(b: u8) -> String {
+    if b.is_ascii_graphic() || b == b' ' {
+        format!(" {:?}", b as char)
+    } else {
+        String::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dump_round_trip() {
+        let path = std::env::temp_dir().join(format!("ts7200-transcript-{}", std::process::id()));
+        let path = path.to_str().unwrap();
+
+        let insn_count = Arc::new(AtomicU64::new(0));
+        let transcript = Transcript::create(path, insn_count.clone()).unwrap();
+        let uart1 = transcript.handle(1);
+        let uart2 = transcript.handle(2);
+
+        insn_count.store(42, Ordering::Relaxed);
+        uart1.record(TranscriptEvent::Rx {
+            byte: b'a',
+            errors: FLAG_FE | FLAG_OE,
+        });
+        uart1.record(TranscriptEvent::Tx {
+            byte: 0xc0,
+            dropped: true,
+        });
+        insn_count.store(1337, Ordering::Relaxed);
+        uart2.record(TranscriptEvent::Cts(false));
+        uart2.record(TranscriptEvent::Break(true));
+        uart2.record(TranscriptEvent::Break(false));
+        transcript.flush();
+
+        let mut out = Vec::new();
+        dump_transcript(File::open(path).unwrap(), &mut out).unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        // strip the (non-deterministic) host timestamps
+        let out = String::from_utf8(out).unwrap();
+        let lines: Vec<_> = out.lines().map(|l| l.split_at(14).1).collect();
+        assert_eq!(
+            lines,
+            [
+                "insn           42  uart1  rx  0x61 'a' FE OE",
+                "insn           42  uart1  tx  0xc0 (dropped by peer)",
+                "insn         1337  uart2  cts 0",
+                "insn         1337  uart2  brk start",
+                "insn         1337  uart2  brk end",
+            ]
+        );
+    }
+
+    #[test]
+    fn dump_rejects_garbage() {
+        let mut out = Vec::new();
+        assert!(dump_transcript(&b"not a transcript"[..], &mut out).is_err());
+    }
+}
@@ -1,40 +1,112 @@
-/// TS-7200 VIC Interrupts, as enumerated in section 6.1.2 of the
-/// EP93xx User's Guide.
-#[derive(Clone, Copy, Debug, PartialEq)]
-pub enum Interrupt {
-    Tc1Ui,
-    Tc2Ui,
-    Uart1RxIntr1,
-    Uart1TxIntr1,
-    Uart2RxIntr2,
-    Uart2TxIntr2,
-    Uart3RxIntr3,
-    Uart3TxIntr3,
-    Tc3Ui,
-    IntUart1,
-    IntUart2,
-    IntUart3,
-}
+macro_rules! interrupts {
+    ($($(#[$meta:meta])* $name:ident = $index:literal,)*) => {
+        /// TS-7200 VIC Interrupts, as enumerated in section 6.1.2 of the
+        /// EP93xx User's Guide.
+        #[derive(Clone, Copy, Debug, PartialEq)]
+        pub enum Interrupt {
+            $($(#[$meta])* $name,)*
+        }
 
-impl Interrupt {
-    fn overall_index(self) -> u8 {
-        use Interrupt::*;
-        match self {
-            Tc1Ui => 4,
-            Tc2Ui => 5,
-            Uart1RxIntr1 => 23,
-            Uart1TxIntr1 => 24,
-            Uart2RxIntr2 => 25,
-            Uart2TxIntr2 => 26,
-            Uart3RxIntr3 => 27,
-            Uart3TxIntr3 => 28,
-            Tc3Ui => 51,
-            IntUart1 => 52,
-            IntUart2 => 54,
-            IntUart3 => 55,
+        impl Interrupt {
+            fn overall_index(self) -> u8 {
+                use Interrupt::*;
+                match self {
+                    $($name => $index,)*
+                }
+            }
+
+            /// Look up the interrupt source wired to a particular bit of a
+            /// specific VIC bank. Returns `None` for unused bits.
+            pub fn from_bank_index(bank: u8, index: u8) -> Option<Interrupt> {
+                use Interrupt::*;
+                match (bank - 1) * 32 + index {
+                    $($index => Some($name),)*
+                    _ => None,
+                }
+            }
         }
-    }
+    };
+}
+
+interrupts! {
+    // -- VIC1 -- //
+    /// ARM debug comms rx
+    CommRx = 2,
+    /// ARM debug comms tx
+    CommTx = 3,
+    Tc1Ui = 4,
+    Tc2Ui = 5,
+    /// AC97 controller
+    AacIntr = 6,
+    DmaM2P0 = 7,
+    DmaM2P1 = 8,
+    DmaM2P2 = 9,
+    DmaM2P3 = 10,
+    DmaM2P4 = 11,
+    DmaM2P5 = 12,
+    DmaM2P6 = 13,
+    DmaM2P7 = 14,
+    DmaM2P8 = 15,
+    DmaM2P9 = 16,
+    DmaM2M0 = 17,
+    DmaM2M1 = 18,
+    Gpio0Intr = 19,
+    Gpio1Intr = 20,
+    Gpio2Intr = 21,
+    Gpio3Intr = 22,
+    Uart1RxIntr1 = 23,
+    Uart1TxIntr1 = 24,
+    Uart2RxIntr2 = 25,
+    Uart2TxIntr2 = 26,
+    Uart3RxIntr3 = 27,
+    Uart3TxIntr3 = 28,
+    /// Keypad
+    IntKey = 29,
+    /// Touchscreen
+    IntTouch = 30,
+
+    // -- VIC2 -- //
+    IntExt0 = 32,
+    IntExt1 = 33,
+    IntExt2 = 34,
+    /// 64Hz tick (cleared via the Syscon's TEOI register)
+    Tintr = 35,
+    /// Watchdog
+    WeInt = 36,
+    IntRtc = 37,
+    IntIrda = 38,
+    /// Ethernet MAC
+    IntMac = 39,
+    IntExt3 = 40,
+    /// Raster programmable interrupt
+    IntProg = 41,
+    /// 1Hz clock
+    Clk1Hz = 42,
+    VSync = 43,
+    IntVideoFifo = 44,
+    IntSsp1Rx = 45,
+    IntSsp1Tx = 46,
+    Gpio4Intr = 47,
+    Gpio5Intr = 48,
+    Gpio6Intr = 49,
+    Gpio7Intr = 50,
+    Tc3Ui = 51,
+    IntUart1 = 52,
+    SspIntr = 53,
+    IntUart2 = 54,
+    IntUart3 = 55,
+    /// USB host
+    UshIntr = 56,
+    /// Ethernet power management
+    IntPme = 57,
+    IntDsp = 58,
+    /// Combined GPIO port A/B interrupt
+    GpioIntr = 59,
+    /// I2S / SAI
+    I2sIntr = 60,
+}
 
+impl Interrupt {
     /// Get VIC bank associated with the interrupt
     pub fn bank(self) -> u8 {
         if self.overall_index() < 32 {
@@ -49,3 +121,28 @@ impl Interrupt {
         self.overall_index() & !0x20
     }
 }
+
+/// Lists the interrupt sources corresponding to the set bits in a VIC
+/// register (e.g: `[Tc1Ui, bit31]`)
+pub struct SourceList {
+    pub bank: u8,
+    pub mask: u32,
+}
+
+impl std::fmt::Display for SourceList {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "[")?;
+        let mut first = true;
+        for index in (0..32).filter(|i| self.mask & (1 << i) != 0) {
+            if !first {
+                write!(f, ", ")?;
+            }
+            first = false;
+            match Interrupt::from_bank_index(self.bank, index) {
+                Some(int) => write!(f, "{:?}", int)?,
+                None => write!(f, "bit{}", index)?,
+            }
+        }
+        write!(f, "]")
+    }
+}
@@ -7,6 +7,14 @@ pub use vicmanager::VicManager;
 use crate::devices::{Device, Probe};
 use crate::memory::{MemException::*, MemResult, Memory};
 
+use interrupts::SourceList;
+
+// Hardware priority levels, from highest to lowest. Vectored interrupts use the
+// index of their VectCntl slot as their priority.
+const PRIORITY_DAISY_CHAIN: u8 = 16;
+const PRIORITY_NON_VECTORED: u8 = 17;
+const PRIORITY_NONE: u8 = 18;
+
 #[derive(Debug, Default)]
 struct VectorEntry {
     source: u8,
@@ -20,28 +28,75 @@ struct VectorEntry {
 #[derive(Debug)]
 pub struct Vic {
     label: &'static str,
+    bank: u8,
     status: u32,          // Interrupts currently hardware asserted
     enabled: u32,         // Enabled interrupts
     select: u32,          // FIQ mode interrupts
     software_status: u32, // Software asserted interrupts
+    protection: bool,     // Only allow privileged mode accesses
 
     default_isr: u32,
 
     vector_entries: [VectorEntry; 16],
+    // priorities of the interrupts currently being serviced (i.e: VectAddr was
+    // read, but hasn't been written to yet)
+    priority_stack: Vec<u8>,
+
+    // whether the CPU is currently executing in a privileged mode
+    privileged: bool,
+    // whether accesses are coming from the debugger (i.e: they shouldn't have
+    // any side effects)
+    debugger: bool,
 }
 
 impl Vic {
     /// Create a new Vic
-    pub fn new(label: &'static str) -> Vic {
+    pub fn new(label: &'static str, bank: u8) -> Vic {
         Vic {
             label,
+            bank,
             status: 0,
             enabled: 0,
             select: 0,
             software_status: 0,
+            protection: false,
             default_isr: 0,
             vector_entries: Default::default(),
+            priority_stack: Vec::new(),
+            privileged: true,
+            debugger: false,
+        }
+    }
+
+    /// Set whether subsequent accesses come from a privileged CPU mode
+    pub fn set_privileged(&mut self, privileged: bool) {
+        self.privileged = privileged;
+    }
+
+    /// Set whether subsequent accesses come from the debugger
+    pub fn set_debugger_access(&mut self, debugger: bool) {
+        self.debugger = debugger;
+    }
+
+    /// The Protection register can only ever be accessed from privileged
+    /// modes, and once the protection bit is set, the same applies to every
+    /// other register. The debugger is exempt, regardless of the CPU's mode.
+    fn check_privilege(&self, offset: u32) -> MemResult<()> {
+        if self.debugger || self.privileged || (offset != 0x20 && !self.protection) {
+            return Ok(());
         }
+
+        let msg = if offset == 0x20 {
+            "Cannot access VIC Protection register from User mode"
+        } else {
+            "Cannot access VIC from User mode while the Protection bit is set"
+        };
+
+        Err(ContractViolation {
+            msg: msg.to_string(),
+            severity: log::Level::Error,
+            stub_val: None,
+        })
     }
 
     fn rawstatus(&self) -> u32 {
@@ -54,7 +109,7 @@ impl Vic {
 
     /// Check if an IRQ should be requested
     pub fn irq(&self) -> bool {
-        (self.enabled_active_interrupts() & !self.select) != 0
+        self.highest_pending_irq().is_some()
     }
 
     /// Check if an FIQ should be requested
@@ -62,31 +117,101 @@ impl Vic {
         (self.enabled_active_interrupts() & self.select) != 0
     }
 
-    fn isr_address(&self) -> u32 {
-        if self.fiq() || !self.irq() {
-            self.default_isr
+    /// Priority of the interrupt currently being serviced
+    fn current_priority(&self) -> u8 {
+        self.priority_stack.last().copied().unwrap_or(PRIORITY_NONE)
+    }
+
+    /// Returns the priority and ISR address of the highest priority IRQ which
+    /// isn't masked out by the interrupt currently being serviced.
+    fn highest_pending_irq(&self) -> Option<(u8, u32)> {
+        let irqs = self.enabled_active_interrupts() & !self.select;
+        if irqs == 0 {
+            return None;
+        }
+
+        let (priority, isr_addr) = self
+            .vector_entries
+            .iter()
+            .enumerate()
+            .find_map(|(i, entry)| {
+                if entry.enabled && (irqs & (1 << entry.source)) != 0 {
+                    Some((i as u8, entry.isr_addr))
+                } else {
+                    None
+                }
+            })
+            .unwrap_or((PRIORITY_NON_VECTORED, self.default_isr));
+
+        if priority < self.current_priority() {
+            Some((priority, isr_addr))
         } else {
-            let irqs = self.enabled_active_interrupts() & !self.select;
-            self.vector_entries
-                .iter()
-                .find_map(|entry| {
-                    if entry.enabled && (irqs & (1 << entry.source)) != 0 {
-                        Some(entry.isr_addr)
-                    } else {
-                        None
-                    }
-                })
-                .unwrap_or(self.default_isr)
+            None
+        }
+    }
+
+    /// Check if there is a pending vectored IRQ which isn't masked out by the
+    /// interrupt currently being serviced.
+    fn vectored_irq(&self) -> bool {
+        match self.highest_pending_irq() {
+            Some((priority, _)) => priority < PRIORITY_DAISY_CHAIN,
+            None => false,
+        }
+    }
+
+    /// Check if an IRQ from a daisy-chained VIC should be let through.
+    fn daisy_chain_unmasked(&self) -> bool {
+        PRIORITY_DAISY_CHAIN < self.current_priority()
+    }
+
+    /// Reading VectAddr returns the ISR address of the highest priority IRQ,
+    /// and masks out any interrupts with the same or lower priority until
+    /// VectAddr is written to.
+    ///
+    /// Reads which don't find a pending IRQ keep the current priority level,
+    /// such that the matching write doesn't unmask an interrupt that's still
+    /// being serviced.
+    fn read_vect_addr(&mut self) -> u32 {
+        let (priority, isr_addr) = self
+            .highest_pending_irq()
+            .unwrap_or((self.current_priority(), self.default_isr));
+        if !self.debugger {
+            self.priority_stack.push(priority);
+        }
+        isr_addr
+    }
+
+    /// Writing to VectAddr signals that the current interrupt has been
+    /// serviced, restoring the previous priority level.
+    fn write_vect_addr(&mut self) {
+        if self.debugger {
+            return;
+        }
+        if self.priority_stack.pop().is_none() {
+            debug!("{} VectAddr written without a matching read", self.label);
+        }
+    }
+
+    fn sources(&self, mask: u32) -> SourceList {
+        SourceList {
+            bank: self.bank,
+            mask,
         }
     }
 
     /// Request an interrupt from a hardware source
     pub fn assert_interrupt(&mut self, source: u8) {
+        if self.status & (1 << source) == 0 {
+            trace!("{} asserting {}", self.label, self.sources(1 << source));
+        }
         self.status |= 1 << source;
     }
 
     /// Clear an interrupt from a hardware source
     pub fn clear_interrupt(&mut self, source: u8) {
+        if self.status & (1 << source) != 0 {
+            trace!("{} clearing {}", self.label, self.sources(1 << source));
+        }
         self.status &= !(1 << source);
     }
 }
@@ -118,12 +243,29 @@ impl Device for Vic {
             0xfe0..=0xfe4 => "PeriphIDX",
             _ => return Probe::Unmapped,
         };
-        Probe::Register(reg)
+
+        // name the interrupt sources behind any set bits
+        let mask = match offset {
+            0x00 => self.enabled_active_interrupts() & !self.select,
+            0x04 => self.enabled_active_interrupts() & self.select,
+            0x08 => self.rawstatus(),
+            0x0c => self.select,
+            0x10 => self.enabled,
+            0x18 => self.software_status,
+            0x200..=0x23c => 1 << self.vector_entries[((offset - 0x200) / 4) as usize].source,
+            _ => return Probe::Register(reg),
+        };
+        Probe::RegisterState {
+            name: reg,
+            state: self.sources(mask).to_string(),
+        }
     }
 }
 
 impl Memory for Vic {
     fn r32(&mut self, offset: u32) -> MemResult<u32> {
+        self.check_privilege(offset)?;
+
         match offset {
             0x00 => Ok(self.enabled_active_interrupts() & !self.select),
             0x04 => Ok(self.enabled_active_interrupts() & self.select),
@@ -133,9 +275,8 @@ impl Memory for Vic {
             0x14 => Err(InvalidAccess),
             0x18 => Ok(self.software_status),
             0x1c => Err(InvalidAccess),
-            // TODO: enforce that VIC Protection bit must be accessed in privileged mode
-            0x20 => Err(StubRead(0)),
-            0x30 => Ok(self.isr_address()),
+            0x20 => Ok(self.protection as u32),
+            0x30 => Ok(self.read_vect_addr()),
             0x34 => Ok(self.default_isr),
             0x100..=0x13c => {
                 let index = ((offset - 0x100) / 4) as usize;
@@ -157,20 +298,34 @@ impl Memory for Vic {
     }
 
     fn w32(&mut self, offset: u32, val: u32) -> MemResult<()> {
+        self.check_privilege(offset)?;
+
         match offset {
             0x00 => Err(InvalidAccess),
             0x04 => Err(InvalidAccess),
             0x08 => Err(InvalidAccess),
-            0x0c => Ok(self.select = val),
-            0x10 => Ok(self.enabled = val),
-            0x14 => Ok(self.enabled &= !val),
-            0x18 => Ok(self.software_status |= val),
-            0x1c => Ok(self.software_status &= !val),
-            // TODO: enforce that VIC Protection bit must be accessed in privileged mode
-            0x20 => Err(StubWrite),
-            // Writing to this signals to the Vic that the interrupt has been serviced.
-            // We don't implement the behavior that cares about that for now, so no-op.
-            0x30 => Ok(()),
+            0x0c => {
+                trace!("{} IntSelect (FIQ) = {}", self.label, self.sources(val));
+                Ok(self.select = val)
+            }
+            0x10 => {
+                trace!("{} IntEnable = {}", self.label, self.sources(val));
+                Ok(self.enabled = val)
+            }
+            0x14 => {
+                trace!("{} IntEnable -= {}", self.label, self.sources(val));
+                Ok(self.enabled &= !val)
+            }
+            0x18 => {
+                trace!("{} SoftInt += {}", self.label, self.sources(val));
+                Ok(self.software_status |= val)
+            }
+            0x1c => {
+                trace!("{} SoftInt -= {}", self.label, self.sources(val));
+                Ok(self.software_status &= !val)
+            }
+            0x20 => Ok(self.protection = val & 1 != 0),
+            0x30 => Ok(self.write_vect_addr()),
             0x34 => Ok(self.default_isr = val),
             0x100..=0x13c => {
                 let index = ((offset - 0x100) / 4) as usize;
@@ -181,6 +336,16 @@ impl Memory for Vic {
                 let entry = &mut self.vector_entries[index];
                 entry.enabled = (val & 0x20) != 0;
                 entry.source = (val & 0x1f) as u8;
+                trace!(
+                    "{} VectCntl{} = {} (enabled: {})",
+                    self.label,
+                    index,
+                    SourceList {
+                        bank: self.bank,
+                        mask: 1 << entry.source
+                    },
+                    entry.enabled
+                );
 
                 Ok(())
             }
@@ -192,3 +357,76 @@ impl Memory for Vic {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const VECT0_ISR: u32 = 0x1000;
+    const VECT1_ISR: u32 = 0x2000;
+    const DEFAULT_ISR: u32 = 0x3000;
+
+    /// A VIC with source 5 in VectCntl0, source 3 in VectCntl1, and source 7
+    /// left non-vectored.
+    fn vic() -> Vic {
+        let mut vic = Vic::new("vic", 1);
+        vic.w32(0x100, VECT0_ISR).unwrap();
+        vic.w32(0x200, 0x20 | 5).unwrap();
+        vic.w32(0x104, VECT1_ISR).unwrap();
+        vic.w32(0x204, 0x20 | 3).unwrap();
+        vic.w32(0x34, DEFAULT_ISR).unwrap();
+        vic.w32(0x10, (1 << 5) | (1 << 3) | (1 << 7)).unwrap();
+        vic
+    }
+
+    #[test]
+    fn priority_masking() {
+        let mut vic = vic();
+
+        vic.assert_interrupt(3);
+        assert!(vic.irq());
+        assert_eq!(vic.r32(0x30).unwrap(), VECT1_ISR);
+        // interrupts of the same or lower priority are masked while servicing
+        assert!(!vic.irq());
+        assert!(!vic.daisy_chain_unmasked());
+        vic.assert_interrupt(7);
+        assert!(!vic.irq());
+
+        // ...but higher priority ones preempt it
+        vic.assert_interrupt(5);
+        assert!(vic.irq());
+        assert_eq!(vic.r32(0x30).unwrap(), VECT0_ISR);
+        vic.clear_interrupt(5);
+        vic.w32(0x30, 0).unwrap();
+
+        // back to servicing source 3
+        assert!(!vic.irq());
+        vic.clear_interrupt(3);
+        vic.w32(0x30, 0).unwrap();
+
+        assert!(vic.irq());
+        assert_eq!(vic.r32(0x30).unwrap(), DEFAULT_ISR);
+        // the daisy chain outranks non-vectored interrupts
+        assert!(vic.daisy_chain_unmasked());
+        vic.w32(0x30, 0).unwrap();
+        assert!(vic.priority_stack.is_empty());
+    }
+
+    #[test]
+    fn debugger_vect_addr_access() {
+        let mut vic = vic();
+        vic.assert_interrupt(3);
+        assert_eq!(vic.r32(0x30).unwrap(), VECT1_ISR);
+
+        // poking at VectAddr from the debugger mustn't change the priority
+        vic.set_debugger_access(true);
+        vic.assert_interrupt(5);
+        assert_eq!(vic.r32(0x30).unwrap(), VECT0_ISR);
+        vic.w32(0x30, 0).unwrap();
+        vic.w32(0x30, 0).unwrap();
+        vic.set_debugger_access(false);
+
+        assert_eq!(vic.priority_stack, vec![1]);
+        assert!(vic.irq());
+    }
+}
@@ -1,12 +1,16 @@
 use crate::devices::{Device, Probe};
 use crate::memory::{MemResult, Memory};
 
-use super::{Interrupt, Vic};
+use super::{Interrupt, Vic, PRIORITY_DAISY_CHAIN};
 
 /// VIC Manager module.
 ///
 /// Contains the two VIC units from the EP9302, and handles the daisy chaining
 /// logic (As described in section 6.1 of the EP93xx user's guide)
+///
+/// VIC2's IRQ output is chained into VIC1, where it has a lower priority than
+/// any of VIC1's vectored interrupts, but a higher priority than VIC1's
+/// non-vectored interrupts.
 #[derive(Debug)]
 pub struct VicManager {
     vic1: Vic,
@@ -18,8 +22,8 @@ impl VicManager {
     #[allow(clippy::new_without_default)] // Prefer to force explicit creation
     pub fn new() -> Self {
         VicManager {
-            vic1: Vic::new("vic1"),
-            vic2: Vic::new("vic2"),
+            vic1: Vic::new("vic1", 1),
+            vic2: Vic::new("vic2", 2),
         }
     }
 
@@ -30,7 +34,21 @@ impl VicManager {
 
     /// Check if an FIQ should be requested
     pub fn irq(&self) -> bool {
-        self.vic1.irq() || self.vic2.irq()
+        self.vic1.irq() || (self.vic2.irq() && self.vic1.daisy_chain_unmasked())
+    }
+
+    /// Set whether subsequent accesses come from a privileged CPU mode
+    pub fn set_privileged(&mut self, privileged: bool) {
+        self.vic1.set_privileged(privileged);
+        self.vic2.set_privileged(privileged);
+    }
+
+    /// Set whether subsequent accesses come from the debugger, in which case
+    /// reading VectAddr doesn't affect the hardware priority logic, and the
+    /// Protection bit isn't enforced.
+    pub fn set_debugger_access(&mut self, debugger: bool) {
+        self.vic1.set_debugger_access(debugger);
+        self.vic2.set_debugger_access(debugger);
     }
 
     fn bank(&mut self, bank: u8) -> &mut Vic {
@@ -76,14 +94,21 @@ impl Memory for VicManager {
     fn r32(&mut self, offset: u32) -> MemResult<u32> {
         match offset {
             0x30 => {
+                self.vic1.check_privilege(offset)?;
+
                 // Daisy chain the VICVectAddr register
-                if self.vic1.irq() {
-                    self.vic1.r32(0x30)
-                } else if self.vic2.irq() {
-                    self.vic2.r32(0x30)
+                if self.vic1.vectored_irq() {
+                    Ok(self.vic1.read_vect_addr())
+                } else if self.vic2.irq() && self.vic1.daisy_chain_unmasked() {
+                    if !self.vic1.debugger {
+                        self.vic1.priority_stack.push(PRIORITY_DAISY_CHAIN);
+                    }
+                    Ok(self.vic2.read_vect_addr())
                 } else {
-                    // TODO: Result in this case unclear, needs hardware checking
-                    self.vic1.r32(0x34) // Read the VIC1DefVectAddr register
+                    // Falls back to VIC1's non-vectored ISR address (i.e: the
+                    // VIC1DefVectAddr register).
+                    // TODO: Result when no IRQ is pending unclear, needs hardware checking
+                    Ok(self.vic1.read_vect_addr())
                 }
             }
             _ => {
@@ -97,10 +122,24 @@ impl Memory for VicManager {
     }
 
     fn w32(&mut self, offset: u32, val: u32) -> MemResult<()> {
-        if offset < 0x10000 {
-            self.vic1.w32(offset, val)
-        } else {
-            self.vic2.w32(offset - 0x10000, val)
+        match offset {
+            0x30 => {
+                self.vic1.check_privilege(offset)?;
+
+                // If the interrupt being serviced came through the daisy chain, VIC2
+                // must also be told that it has been serviced.
+                if self.vic1.current_priority() == PRIORITY_DAISY_CHAIN {
+                    self.vic2.write_vect_addr();
+                }
+                self.vic1.w32(0x30, val)
+            }
+            _ => {
+                if offset < 0x10000 {
+                    self.vic1.w32(offset, val)
+                } else {
+                    self.vic2.w32(offset - 0x10000, val)
+                }
+            }
         }
     }
 }
@@ -43,11 +43,126 @@ UART CONFIGURATION:
     * stdio
         - Use the process's stdin / stdout
         - Sets the terminal to "raw" mode
-    * tcp:[host]:port
+        - Ctrl-C terminates the emulator, unless --stdio-leader is set
+    * pty
+        - Allocate a pseudo-terminal (e.g: /dev/pts/N) for terminal programs
+          such as gtkterm, screen, or minicom to connect to
+        - The pty's path is printed on startup
+    * tcp:[host]:port[,server[,wait]]
         - Connect to a tcp server
         - "host" defaults to 127.0.0.1 (localhost)
-
-    e.g: `--uart1=file:/dev/null,in=/tmp/trainin.pipe`, `--uart1=tcp::3018`
+        - server: listen for connections instead. Clients can (re)connect at
+          any time, and output is discarded while no client is connected.
+        - wait: block boot until a client connects
+    * unix:/path/to/sock[,server[,wait]]
+        - Connect to a unix domain socket
+        - server / wait: same as tcp. Any stale socket at the path is
+          replaced, and the socket is removed once the backend is detached.
+    * script:/path/to/script
+        - Drive the UART using an expect-style script, with one command per
+          line (`#` starts a comment):
+            expect <str> [timeout_ms]  wait for <str> in the output (5s default)
+            assert <str>               check <str> is already in the output
+            send <str> [framing|parity]
+                                       send <str> as input, optionally with
+                                       each character flagged as erroneous
+            break                      send a break
+            sleep <ms>                 wait for <ms> milliseconds
+            exit [code]                terminate the emulator
+        - Strings may be quoted, and support \n, \r, \t, \\, \", and \xNN
+        - A failed expect / assert terminates the emulator with exit code 1
+        - Times use the same (host) clock as the emulated timers, so a slow
+          host gets less done in the same time. Leave some headroom!
+        - Line errors / breaks can only be sent when the script is the UART's
+          primary backend (i.e: not a tee= or out: member)
+    * marklin[:/path/to/timeline]
+        - Simulate a Märklin 6051 train controller (intended for UART1)
+        - Logs speed / reverse / switch commands, answers sensor queries, and
+          sets the peer to CTS pacing, 8N2, and 2400 baud
+        - The timeline triggers sensors over time, with one `<ms> <sensor>`
+          entry per line (e.g: `1500 C13`), where <ms> is relative to when
+          the backend was attached
+
+    Backends can also be combined:
+
+    * <cfg>,tee=<cfg>[,tee=<cfg>...]
+        - Also write a copy of all output to each "tee" backend (any input
+          from a tee is ignored)
+    * in:<cfg>,out:<cfg>
+        - Read input from one backend, and write output to another
+
+    e.g: `--uart1=file:/dev/null,in=/tmp/trainin.pipe`, `--uart1=tcp::3018`,
+         `--uart2=tcp::4000,server,wait`, `--uart2=stdio,tee=file:/tmp/uart2.log`,
+         `--uart1=in:tcp::3018,out:file:/tmp/trains.log`
+
+    When debugging with GDB, backends can be swapped at runtime via
+    `monitor uartX attach <cfg>` and `monitor uartX detach` (stdio and
+    `,wait` servers can only be used on startup).
+
+    `--uart-transcript <file>` records every byte crossing each UART (in both
+    directions), alongside CTS / break transitions, each timestamped with the
+    host time and CPU instruction count. Use `ts7200 uart-dump <file>` to
+    pretty-print a transcript.
+
+STDIO LEADER KEY:
+    `--stdio-leader <key>` (e.g: `ctrl-a`) sets a tmux-style leader key for
+    controlling the emulator from the stdio backend. Ctrl-C is then passed
+    through to the UART like any other key. The leader can be followed by:
+
+    * x: quit the emulator
+    * c: send a literal Ctrl-C
+    * b: break into GDB (when a debugger is attached)
+    * s: dump the system state to `snapshot-<instruction count>.log`
+    * r: reset the system (reloading the kernel)
+    * l: toggle logging
+    * <leader>: send the leader key itself
+
+STDIO KEYMAP:
+    `--stdio-keymap <keymap>` controls how keys are translated between the
+    host terminal and the UART, as a preset name, optionally followed by a
+    comma separated list of overrides:
+
+    * presets: gtkterm (default), minicom, raw
+        - gtkterm: backspace is sent as BS, enter as CR, and arrow keys as
+          ANSI cursor keys
+        - minicom: same as gtkterm, except backspace is sent as DEL, and
+          newlines output by the UART are written as CRLF
+        - raw: no translation whatsoever
+    * bs=[bs|del|raw]: what the backspace key is sent as
+    * enter=[cr|lf|crlf|raw]: what the enter key is sent as
+    * onl=[lf|crlf|raw]: what newlines output by the UART are written as
+    * arrows=[ansi|ss3|drop|raw]: what the arrow keys are sent as (e.g:
+      ansi is `ESC [ A`, ss3 is `ESC O A`)
+
+    e.g: `--stdio-keymap gtkterm,onl=crlf,arrows=drop`
+
+PEER CONFIGURATION:
+    The `--uartX-peer` flags describe the behavior of the device on the other
+    end of the UART's wire, as a comma separated list of options:
+
+    * cts=[busy|marklin]
+        - busy: CTS is deasserted while the UART is transmitting (default)
+        - marklin: CTS is deasserted after every byte the peer receives, and
+          reasserted once the Marklin is ready for the next byte. Bytes sent
+          while CTS is deasserted are dropped.
+    * dsr=[0|1], dcd=[0|1], ri=[0|1]
+        - Override the state of UART1's modem status lines. By default, DSR
+          and DCD are asserted whenever a backend is connected. Overrides
+          remain in effect when the backend is swapped via GDB.
+    * line=<format>
+        - The peer's framing, in the usual `<data><parity><stop>` notation
+          (e.g: 8N2). If the guest's LinCtrl settings don't match, received
+          bytes are flagged with framing / parity errors.
+    * baud=<rate>
+        - The peer's baud rate. A warning is logged whenever the guest
+          programs a baud divisor that's more than 3% off.
+    * mismatch=[warn|corrupt]
+        - warn: only log baud rate mismatches (default)
+        - corrupt: additionally garble received bytes, as though the guest
+          was sampling the line at the wrong rate.
+
+    e.g: `--uart1-peer=cts=marklin,line=8N2,baud=2400`,
+         `--uart1-peer=dcd=0,ri=1`
 
 HACKS:
     These hacks should be used with extreme caution, as they greatly compromise
@@ -88,6 +203,31 @@ struct Args {
     #[structopt(long, value_name = "cfg", default_value = "stdio")]
     uart2: uart::UartCfg,
 
+    /// UART1 peer configuration.
+    #[structopt(long, value_name = "cfg", default_value = "")]
+    uart1_peer: uart::PeerCfg,
+
+    /// UART2 peer configuration.
+    #[structopt(long, value_name = "cfg", default_value = "")]
+    uart2_peer: uart::PeerCfg,
+
+    /// Override the UARTs' reference clock frequency (by default, derived
+    /// from the Syscon's PwrCnt register).
+    #[structopt(long, value_name = "hz")]
+    uartclk: Option<u64>,
+
+    /// Leader key for emulator commands entered via stdio (e.g: `ctrl-a`).
+    #[structopt(long, value_name = "key", parse(try_from_str = parse_ctrl_key))]
+    stdio_leader: Option<u8>,
+
+    /// Key translations for the stdio backend (see STDIO KEYMAP).
+    #[structopt(long, value_name = "keymap", default_value = "gtkterm")]
+    stdio_keymap: uart::Keymap,
+
+    /// Record all UART traffic to the specified file.
+    #[structopt(long, value_name = "file")]
+    uart_transcript: Option<String>,
+
     /// HACK: see --hack-uart3-enable
     #[structopt(long, value_name = "cfg", default_value = "none")]
     hack_uart3: uart::UartCfg,
@@ -109,6 +249,30 @@ struct Args {
     no_asan_ram: bool,
 }
 
+/// Pretty-print a UART transcript captured with --uart-transcript
+#[derive(StructOpt)]
+#[structopt(name = "ts7200 uart-dump")]
+struct UartDumpArgs {
+    /// transcript file
+    transcript: String,
+}
+
+/// Parse a control key of the form `ctrl-a` or `^A`.
+fn parse_ctrl_key(s: &str) -> Result<u8, &'static str> {
+    let key = if s.len() == 6 && s.as_bytes()[..5].eq_ignore_ascii_case(b"ctrl-") {
+        s.as_bytes()[5]
+    } else if s.len() == 2 && s.starts_with('^') {
+        s.as_bytes()[1]
+    } else {
+        return Err("expected a control key (e.g: `ctrl-a`)");
+    };
+
+    match key.to_ascii_uppercase() {
+        key @ b'@'..=b'_' => Ok(key & 0x1f),
+        _ => Err("invalid control key"),
+    }
+}
+
 fn wait_for_tcp(port: u16) -> Result<TcpStream, Box<dyn StdError>> {
     let sockaddr = format!("127.0.0.1:{}", port);
     eprintln!("Waiting for a GDB connection on {:?}...", sockaddr);
@@ -127,11 +291,21 @@ fn main() -> Result<(), Box<dyn StdError>> {
         .parse_filters(&std::env::var("RUST_LOG").unwrap_or_default())
         .init();
 
+    // `uart-dump` can't be a regular subcommand, as the emulator's own
+    // arguments require a kernel ELF
+    if std::env::args().nth(1).as_deref() == Some("uart-dump") {
+        let args = UartDumpArgs::from_iter(std::env::args().skip(1));
+        let file = fs::File::open(args.transcript)?;
+        let stdout = std::io::stdout();
+        uart::dump_transcript(std::io::BufReader::new(file), stdout.lock())?;
+        return Ok(());
+    }
+
     let args = Args::from_args();
 
     let num_stdio_uarts = [&args.uart1, &args.uart2, &args.hack_uart3]
         .iter()
-        .map(|cfg| matches!(cfg, uart::UartCfg::Stdio { .. }))
+        .map(|cfg| cfg.uses_stdio())
         .filter(|x| *x)
         .count();
     if num_stdio_uarts > 1 {
@@ -147,11 +321,42 @@ fn main() -> Result<(), Box<dyn StdError>> {
         system.devices_mut().hack_uart3_enable();
     }
 
+    if let Some(hz) = args.uartclk {
+        // the baud rate is derived from UARTCLK / 16
+        if hz < 16 {
+            return Err("invalid UARTCLK frequency (must be at least 16 Hz)".into());
+        }
+        system.devices_mut().set_uartclk_override(hz);
+    }
+
+    let transcript = match &args.uart_transcript {
+        Some(path) => Some(uart::Transcript::create(path, system.insn_count())?),
+        None => None,
+    };
+
     // hook up the uarts
-    args.uart1.apply(&mut system.devices_mut().uart1)?;
-    args.uart2.apply(&mut system.devices_mut().uart2)?;
+    let stdio = uart::StdioCfg {
+        leader: args.stdio_leader,
+        host_cmds: Some(system.host_cmd_sender()),
+        keymap: args.stdio_keymap,
+    };
+    args.uart1
+        .apply_with_stdio(&mut system.devices_mut().uart1, &stdio)?;
+    args.uart2
+        .apply_with_stdio(&mut system.devices_mut().uart2, &stdio)?;
     if let Some(uart3) = &mut system.devices_mut().uart3_hack {
-        args.hack_uart3.apply(uart3)?;
+        args.hack_uart3.apply_with_stdio(uart3, &stdio)?;
+    }
+    args.uart1_peer.apply(&mut system.devices_mut().uart1);
+    args.uart2_peer.apply(&mut system.devices_mut().uart2);
+
+    if let Some(transcript) = &transcript {
+        let devices = system.devices_mut();
+        devices.uart1.set_transcript(Some(transcript.handle(1)));
+        devices.uart2.set_transcript(Some(transcript.handle(2)));
+        if let Some(uart3) = &mut devices.uart3_hack {
+            uart3.set_transcript(Some(transcript.handle(3)));
+        }
     }
 
     // apply uart hax
@@ -245,5 +450,13 @@ fn main() -> Result<(), Box<dyn StdError>> {
         }
     }
 
+    if let Some(code) = system.exit_code() {
+        // tear down the system first, so the UART backends get a chance to
+        // clean up (e.g: restoring the terminal, flushing the transcript)
+        drop(system);
+        drop(transcript);
+        std::process::exit(code);
+    }
+
     Ok(())
 }
@@ -3,13 +3,21 @@ use core::convert::TryInto;
 use armv4t_emu::reg;
 use gdbstub::arch;
 use gdbstub::arch::arm::reg::id::ArmCoreRegId;
+use gdbstub::outputln;
 use gdbstub::target::ext::base::singlethread::{ResumeAction, SingleThreadOps, StopReason};
 use gdbstub::target::ext::breakpoints::WatchKind;
+use gdbstub::target::ext::monitor_cmd::ConsoleOutput;
 use gdbstub::target::{self, Target, TargetResult};
 
 use super::{BlockMode, Event, FatalError, Ts7200};
+use crate::devices::uart::{StdioCfg, UartCfg};
 use crate::memory::Memory;
 
+const MONITOR_HELP: &str = "\
+Commands:
+    uart<N> attach <cfg>  Attach a new backend to UART N (same syntax as --uartN)
+    uart<N> detach        Detach UART N from its backend";
+
 impl Target for Ts7200 {
     type Arch = arch::arm::Armv4t;
     type Error = FatalError;
@@ -25,6 +33,10 @@ impl Target for Ts7200 {
     fn hw_watchpoint(&mut self) -> Option<target::ext::breakpoints::HwWatchpointOps<Self>> {
         Some(self)
     }
+
+    fn monitor_cmd(&mut self) -> Option<target::ext::monitor_cmd::MonitorCmdOps<Self>> {
+        Some(self)
+    }
 }
 
 /// Turn a `ArmCoreRegId` into an internal register number of `armv4t_emu`.
@@ -77,6 +89,7 @@ impl SingleThreadOps for Ts7200 {
                 kind: WatchKind::Read,
                 addr,
             },
+            Event::Interrupted => StopReason::GdbInterrupt,
         })
     }
 
@@ -137,28 +150,32 @@ impl SingleThreadOps for Ts7200 {
     }
 
     fn read_addrs(&mut self, start_addr: u32, data: &mut [u8]) -> TargetResult<(), Self> {
-        for (addr, val) in (start_addr..).zip(data.iter_mut()) {
-            *val = match self.devices.r8(addr) {
-                Ok(val) => val,
-                Err(_) => {
-                    // the only errors that RAM emits are accessing uninitialized memory, which gdb
-                    // will do _a lot_. We'll just squelch these errors...
-                    if addr < 0x0200_0000 {
-                        0x00
-                    } else {
-                        return Err(().into());
+        self.devices.debugger_access(|devices| {
+            for (addr, val) in (start_addr..).zip(data.iter_mut()) {
+                *val = match devices.r8(addr) {
+                    Ok(val) => val,
+                    Err(_) => {
+                        // the only errors that RAM emits are accessing uninitialized memory, which
+                        // gdb will do _a lot_. We'll just squelch these errors...
+                        if addr < 0x0200_0000 {
+                            0x00
+                        } else {
+                            return Err(().into());
+                        }
                     }
                 }
             }
-        }
-        Ok(())
+            Ok(())
+        })
     }
 
     fn write_addrs(&mut self, start_addr: u32, data: &[u8]) -> TargetResult<(), Self> {
-        for (addr, val) in (start_addr..).zip(data.iter().copied()) {
-            self.devices.w8(addr, val).map_err(drop)?;
-        }
-        Ok(())
+        self.devices.debugger_access(|devices| {
+            for (addr, val) in (start_addr..).zip(data.iter().copied()) {
+                devices.w8(addr, val).map_err(drop)?;
+            }
+            Ok(())
+        })
     }
 }
 
@@ -204,3 +221,82 @@ impl target::ext::breakpoints::HwWatchpoint for Ts7200 {
         Ok(true)
     }
 }
+
+impl Ts7200 {
+    /// Handle a `monitor uart<N> ...` command, returning a message to display
+    /// to the user.
+    fn handle_uart_cmd<'a>(
+        &mut self,
+        name: &str,
+        mut args: impl Iterator<Item = &'a str>,
+    ) -> Result<String, String> {
+        // stdio itself can't be attached at runtime, but scripts need a way to
+        // stop the system
+        let stdio = StdioCfg {
+            host_cmds: Some(self.host_cmd_tx.clone()),
+            ..StdioCfg::default()
+        };
+        let devices = &mut self.devices;
+        let uart = match name {
+            "uart1" => &mut devices.uart1,
+            "uart2" => &mut devices.uart2,
+            "uart3" => devices
+                .uart3_hack
+                .as_mut()
+                .ok_or("uart3 isn't enabled (see --hack-uart3-enable)")?,
+            _ => return Err(format!("unknown uart {:?}", name)),
+        };
+
+        let cfg = match args.next() {
+            Some("attach") => {
+                let cfg = args.next().ok_or("expected a uart cfg")?;
+                let cfg: UartCfg = cfg
+                    .parse()
+                    .map_err(|e| format!("invalid uart cfg: {}", e))?;
+                // stdio is already in use by the terminal running GDB (or
+                // by another UART), and can only be configured on startup
+                if cfg.uses_stdio() {
+                    return Err("cannot attach stdio at runtime".into());
+                }
+                // the system (and GDB) would be stuck until a client connects
+                if cfg.waits_for_client() {
+                    return Err("cannot wait for a client at runtime (drop `,wait`)".into());
+                }
+                cfg
+            }
+            Some("detach") => UartCfg::None,
+            _ => return Err("expected `attach <cfg>` or `detach`".into()),
+        };
+
+        cfg.apply(uart, &stdio).map_err(|e| e.to_string())?;
+        Ok(format!("{} is now using {}", name, cfg))
+    }
+}
+
+impl target::ext::monitor_cmd::MonitorCmd for Ts7200 {
+    fn handle_monitor_cmd(
+        &mut self,
+        cmd: &[u8],
+        mut out: ConsoleOutput<'_>,
+    ) -> Result<(), Self::Error> {
+        let cmd = match core::str::from_utf8(cmd) {
+            Ok(cmd) => cmd,
+            Err(_) => {
+                outputln!(out, "command must be valid UTF-8");
+                return Ok(());
+            }
+        };
+
+        let mut args = cmd.split_whitespace();
+        match args.next() {
+            Some(uart) if uart.starts_with("uart") => match self.handle_uart_cmd(uart, args) {
+                Ok(msg) => outputln!(out, "{}", msg),
+                Err(e) => outputln!(out, "error: {}", e),
+            },
+            Some("help") | None => outputln!(out, "{}", MONITOR_HELP),
+            Some(other) => outputln!(out, "unknown command {:?} (try `monitor help`)", other),
+        }
+
+        Ok(())
+    }
+}
@@ -1,10 +1,14 @@
 use std::io::Read;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 
 use armv4t_emu::{reg, Cpu, Exception, Mode as ArmMode};
-use crossbeam_channel as chan;
+use crossbeam_channel::{self as chan, select};
 use log::*;
 
 use crate::devices;
+use crate::devices::uart::HostCmd;
 use crate::devices::vic::Interrupt;
 use crate::devices::{Device, Probe};
 use crate::memory::{
@@ -20,6 +24,10 @@ mod gdb;
 pub const HLE_BOOTLOADER_SP: u32 = 0x01fd_cf34;
 pub const HLE_BOOTLOADER_LR: u32 = 0x0001_74c8;
 
+/// How long a halted CPU waits for an interrupt before checking for new DMA
+/// requests (i.e: roughly one character time at 115200 baud)
+const HALT_DMA_POLL: Duration = Duration::from_micros(100);
+
 #[derive(Debug)]
 pub enum FatalError {
     FatalMemException {
@@ -40,11 +48,37 @@ pub enum Event {
     Break,
     WatchWrite(u32),
     WatchRead(u32),
+    /// The user asked to break into the debugger (via the stdio leader key)
+    Interrupted,
 }
 
 pub enum BlockMode {
     Blocking,
     NonBlocking,
+    Timeout(Duration),
+}
+
+/// The in-memory sections of a kernel ELF, alongside its entry point.
+struct HleImage {
+    // (address, data)
+    sections: Vec<(usize, Vec<u8>)>,
+    entry: u32,
+}
+
+impl std::fmt::Debug for HleImage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("HleImage")
+            .field(
+                "sections",
+                &self
+                    .sections
+                    .iter()
+                    .map(|(addr, data)| *addr..*addr + data.len())
+                    .collect::<Vec<_>>(),
+            )
+            .field("entry", &self.entry)
+            .finish()
+    }
 }
 
 /// A Ts7200 system
@@ -54,8 +88,17 @@ pub struct Ts7200 {
     frozen: bool,
 
     cpu: Cpu,
+    // shared with anything that timestamps events (e.g: UART transcripts)
+    insn_count: Arc<AtomicU64>,
     devices: Ts7200Bus,
     interrupt_bus: chan::Receiver<(Interrupt, bool)>,
+    host_cmd_tx: chan::Sender<HostCmd>,
+    host_cmd_rx: chan::Receiver<HostCmd>,
+    // set once a host command asks the emulator to exit
+    exit_code: Option<i32>,
+
+    // used to reload the kernel on reset
+    hle_image: HleImage,
 
     watchpoints: Vec<u32>,
     breakpoints: Vec<u32>,
@@ -75,35 +118,68 @@ impl Ts7200 {
             std::io::Error::new(std::io::ErrorKind::InvalidData, "could not parse elf file")
         })?;
 
-        // load directly into the kernel
-        debug!("Setting PC to {:#010x?}", elf_header.entry);
-        let mut cpu = Cpu::new();
-        cpu.reg_set(ArmMode::User, reg::PC, elf_header.entry as u32);
-        cpu.reg_set(ArmMode::User, reg::CPSR, 0xd3); // supervisor mode
-        cpu.reg_set(ArmMode::Supervisor, reg::LR, HLE_BOOTLOADER_LR);
-        cpu.reg_set(ArmMode::Supervisor, reg::SP, HLE_BOOTLOADER_SP);
-
         // create the interrupt bus
         let (interrupt_bus_tx, interrupt_bus_rx) = chan::unbounded();
+        let (host_cmd_tx, host_cmd_rx) = chan::unbounded();
 
-        // initialize system devices (in HLE state)
-        let mut bus = Ts7200Bus::new_hle(interrupt_bus_tx);
-
-        // copy all in-memory sections from the ELF file into system RAM
+        // copy out all in-memory sections from the ELF file
         let sections = elf_header
             .section_headers
             .iter()
-            .filter(|h| h.is_alloc() && h.sh_type != goblin::elf::section_header::SHT_NOBITS);
-        for h in sections {
-            debug!(
-                "loading section {:?} into memory from [{:#010x?}..{:#010x?}]",
-                elf_header.shdr_strtab.get(h.sh_name).unwrap().unwrap(),
-                h.sh_addr,
-                h.sh_addr + h.sh_size,
-            );
-
-            bus.sdram
-                .bulk_write(h.sh_addr as usize, &elf_data[h.file_range()]);
+            .filter(|h| h.is_alloc() && h.sh_type != goblin::elf::section_header::SHT_NOBITS)
+            .map(|h| {
+                debug!(
+                    "loading section {:?} into memory from [{:#010x?}..{:#010x?}]",
+                    elf_header.shdr_strtab.get(h.sh_name).unwrap().unwrap(),
+                    h.sh_addr,
+                    h.sh_addr + h.sh_size,
+                );
+                (h.sh_addr as usize, elf_data[h.file_range()].to_vec())
+            })
+            .collect();
+
+        let insn_count = Arc::new(AtomicU64::new(0));
+        let mut system = Ts7200 {
+            hle: true,
+            frozen: false,
+
+            cpu: Cpu::new(),
+            insn_count,
+            // initialize system devices (in HLE state)
+            devices: Ts7200Bus::new_hle(interrupt_bus_tx),
+            interrupt_bus: interrupt_bus_rx,
+            host_cmd_tx,
+            host_cmd_rx,
+            exit_code: None,
+
+            hle_image: HleImage {
+                sections,
+                entry: elf_header.entry as u32,
+            },
+
+            watchpoints: Vec::new(),
+            breakpoints: Vec::new(),
+        };
+        system.load_hle();
+        Ok(system)
+    }
+
+    /// Reset the CPU, and load the kernel into RAM (as though Redboot had
+    /// just finished loading it).
+    fn load_hle(&mut self) {
+        // load directly into the kernel
+        let entry = self.hle_image.entry;
+        debug!("Setting PC to {:#010x?}", entry);
+        self.cpu = Cpu::new();
+        let cpu = &mut self.cpu;
+        cpu.reg_set(ArmMode::User, reg::PC, entry);
+        cpu.reg_set(ArmMode::User, reg::CPSR, 0xd3); // supervisor mode
+        cpu.reg_set(ArmMode::Supervisor, reg::LR, HLE_BOOTLOADER_LR);
+        cpu.reg_set(ArmMode::Supervisor, reg::SP, HLE_BOOTLOADER_SP);
+
+        // copy all in-memory sections from the ELF file into system RAM
+        for (addr, data) in &self.hle_image.sections {
+            self.devices.sdram.bulk_write(*addr, data);
         }
 
         // Redboot pre-populates up the interrupt vector table with a bunch of
@@ -113,20 +189,56 @@ impl Ts7200 {
         // e.g: SWI correspond to IVT entry 0x08, so to register a SWI handler,
         // write a function pointer to 0x28
         for addr in (0..0x20).step_by(0x04) {
-            bus.sdram.w32(addr, 0xe59f_f018).unwrap();
+            self.devices.sdram.w32(addr, 0xe59f_f018).unwrap();
         }
+    }
 
-        Ok(Ts7200 {
-            hle: true,
-            frozen: false,
+    /// Reset the system, reloading the kernel from scratch.
+    ///
+    /// RAM isn't cleared (just like on real hardware), and the UARTs keep
+    /// their backends / peer configuration.
+    pub fn reset(&mut self) {
+        info!("Resetting the system");
+        self.devices.reset_hle();
+        // discard any interrupts raised prior to the reset
+        for _ in self.interrupt_bus.try_iter() {}
+        self.load_hle();
+    }
 
-            cpu,
-            devices: bus,
-            interrupt_bus: interrupt_bus_rx,
+    /// Dump the system's state to a file, returning the file's name.
+    pub fn snapshot(&self) -> std::io::Result<String> {
+        let path = format!("snapshot-{}.log", self.insn_count.load(Ordering::Relaxed));
+        std::fs::write(&path, format!("{:#x?}", self))?;
+        Ok(path)
+    }
 
-            watchpoints: Vec::new(),
-            breakpoints: Vec::new(),
-        })
+    /// Return a channel with which system-level commands can be issued (e.g:
+    /// by the stdio backend's leader key).
+    pub fn host_cmd_sender(&self) -> chan::Sender<HostCmd> {
+        self.host_cmd_tx.clone()
+    }
+
+    /// Handle any pending host commands, returning an event if the system
+    /// should stop (i.e: the user asked to break into the debugger, or to exit
+    /// the emulator).
+    fn handle_host_cmds(&mut self) -> Option<Event> {
+        let mut event = None;
+        while let Ok(cmd) = self.host_cmd_rx.try_recv() {
+            match cmd {
+                HostCmd::Break => event = event.or(Some(Event::Interrupted)),
+                HostCmd::Exit(code) => self.exit_code = Some(code),
+                HostCmd::Snapshot => match self.snapshot() {
+                    Ok(path) => info!("Dumped system state to {}", path),
+                    Err(e) => error!("Failed to dump system state: {}", e),
+                },
+                HostCmd::Reset => self.reset(),
+            }
+        }
+        // once an exit has been requested, the system stays stopped
+        if self.exit_code.is_some() {
+            event = Some(Event::Halted);
+        }
+        event
     }
 
     fn handle_mem_exception(
@@ -199,13 +311,25 @@ impl Ts7200 {
             }};
         }
 
-        match blocking {
-            BlockMode::NonBlocking => check_device_interrupts!(self.interrupt_bus.try_iter()),
-            BlockMode::Blocking => {
-                check_device_interrupts!(std::iter::once(self.interrupt_bus.recv().unwrap())
-                    .chain(self.interrupt_bus.try_iter()))
+        let timeout = match blocking {
+            BlockMode::NonBlocking => {
+                check_device_interrupts!(self.interrupt_bus.try_iter());
+                None
             }
+            BlockMode::Blocking => Some(chan::never()),
+            BlockMode::Timeout(timeout) => Some(chan::after(timeout)),
         };
+        if let Some(timeout) = timeout {
+            select! {
+                recv(self.interrupt_bus) -> interrupt => {
+                    check_device_interrupts!(std::iter::once(interrupt.unwrap())
+                        .chain(self.interrupt_bus.try_iter()))
+                }
+                // requeue the command, as it's handled at the start of the next step
+                recv(self.host_cmd_rx) -> cmd => self.host_cmd_tx.send(cmd.unwrap()).unwrap(),
+                recv(timeout) -> _ => {}
+            }
+        }
 
         if self.devices.vicmgr.fiq() {
             self.cpu.exception(Exception::FastInterrupt);
@@ -222,9 +346,15 @@ impl Ts7200 {
             return Ok(None);
         }
 
+        if let Some(event) = self.handle_host_cmds() {
+            return Ok(Some(event));
+        }
+
         use crate::devices::syscon::PowerState;
         match self.devices.syscon.power_state() {
             PowerState::Run => {
+                self.devices.set_cpu_mode(self.cpu.mode());
+
                 // set up memory sniffer to support watchpoints
                 let mut hit_watchpoint = None;
                 let mut sniffer = MemSniffer::new(&mut self.devices, &self.watchpoints, |access| {
@@ -234,9 +364,16 @@ impl Ts7200 {
                 // step the system
                 let mut mem = MemoryAdapter::new(&mut sniffer);
                 self.cpu.step(&mut mem);
+                self.insn_count.fetch_add(1, Ordering::Relaxed);
                 if let Some(e) = mem.take_exception() {
                     Ts7200::handle_mem_exception(&self.cpu, &self.devices, e)?;
                 }
+                if self.devices.syscon.take_clk_cfg_changed() {
+                    self.devices.update_clocks();
+                }
+                if self.devices.dma.is_active() {
+                    self.devices.service_dma();
+                }
                 self.check_device_interrupts(BlockMode::NonBlocking);
 
                 let pc = self.cpu.reg_get(ArmMode::User, reg::PC);
@@ -262,6 +399,19 @@ impl Ts7200 {
                 }
             }
             PowerState::Halt => {
+                // DMA transfers continue while the CPU is halted, so don't
+                // block indefinitely waiting for an interrupt that may never
+                // come. UART DMA requests aren't signalled over the interrupt
+                // bus, so they're polled instead.
+                let halt_block_mode = if self.devices.dma.is_active() {
+                    self.devices.service_dma();
+                    match halt_block_mode {
+                        BlockMode::Blocking => BlockMode::Timeout(HALT_DMA_POLL),
+                        mode => mode,
+                    }
+                } else {
+                    halt_block_mode
+                };
                 self.check_device_interrupts(halt_block_mode);
                 if self.devices.vicmgr.fiq() || self.devices.vicmgr.irq() {
                     self.devices.syscon.set_run_mode();
@@ -280,8 +430,23 @@ impl Ts7200 {
     /// In HLE mode, a "graceful exit" is when the PC points into the
     /// bootloader's code.
     pub fn run(&mut self) -> Result<(), FatalError> {
-        while self.step(BlockMode::Blocking)? != Some(Event::Halted) {}
-        Ok(())
+        loop {
+            match self.step(BlockMode::Blocking)? {
+                Some(Event::Halted) => return Ok(()),
+                Some(Event::Interrupted) => warn!("No debugger attached (see --gdbport)"),
+                _ => {}
+            }
+        }
+    }
+
+    /// Return a handle to the count of instructions executed by the CPU.
+    pub fn insn_count(&self) -> Arc<AtomicU64> {
+        self.insn_count.clone()
+    }
+
+    /// The exit code requested via [HostCmd::Exit], if any.
+    pub fn exit_code(&self) -> Option<i32> {
+        self.exit_code
     }
 
     pub fn devices_mut(&mut self) -> &mut Ts7200Bus {
@@ -305,6 +470,8 @@ impl Ts7200 {
 #[derive(Debug)]
 pub struct Ts7200Bus {
     pub sdram: devices::Ram, // 32 MB
+    pub dma: devices::Dma,
+    pub irda: devices::Irda,
     pub syscon: devices::Syscon,
     pub timer1: devices::Timer,
     pub timer2: devices::Timer,
@@ -315,23 +482,154 @@ pub struct Ts7200Bus {
     pub vicmgr: devices::vic::VicManager,
 
     interrupt_bus: chan::Sender<(Interrupt, bool)>,
+    uartclk_override: Option<u64>,
 }
 
 impl Ts7200Bus {
     fn new_hle(interrupt_bus: chan::Sender<(Interrupt, bool)>) -> Ts7200Bus {
         use devices::*;
+
+        let mut uart1 = Uart::new_hle("uart1", interrupt_bus.clone(), uart::interrupts::UART1);
+        uart1.enable_uart1_regs();
+
         Ts7200Bus {
             sdram: Ram::new(32 * 1024 * 1024), // 32 MB
-            syscon: Syscon::new_hle(),
+            dma: Dma::new(interrupt_bus.clone()),
+            irda: Irda::new(),
+            syscon: Syscon::new_hle(interrupt_bus.clone()),
             timer1: Timer::new("timer1", interrupt_bus.clone(), Interrupt::Tc1Ui, 16),
             timer2: Timer::new("timer2", interrupt_bus.clone(), Interrupt::Tc2Ui, 16),
             timer3: Timer::new("timer3", interrupt_bus.clone(), Interrupt::Tc3Ui, 32),
-            uart1: Uart::new_hle("uart1", interrupt_bus.clone(), uart::interrupts::UART1),
+            uart1,
             uart2: Uart::new_hle("uart2", interrupt_bus.clone(), uart::interrupts::UART2),
             uart3_hack: None,
             vicmgr: vic::VicManager::new(),
 
             interrupt_bus,
+            uartclk_override: None,
+        }
+    }
+
+    /// Reset all devices to their post-boot state, except for the RAM (whose
+    /// contents survive a reset) and the UARTs (which only have their
+    /// registers reset, keeping any attached backends).
+    fn reset_hle(&mut self) {
+        use devices::*;
+
+        let interrupt_bus = &self.interrupt_bus;
+        self.dma = Dma::new(interrupt_bus.clone());
+        self.irda = Irda::new();
+        // reset in-place, as recreating the syscon would respawn its tick thread
+        self.syscon.reset_hle();
+        self.timer1 = Timer::new("timer1", interrupt_bus.clone(), Interrupt::Tc1Ui, 16);
+        self.timer2 = Timer::new("timer2", interrupt_bus.clone(), Interrupt::Tc2Ui, 16);
+        self.timer3 = Timer::new("timer3", interrupt_bus.clone(), Interrupt::Tc3Ui, 32);
+        self.vicmgr = vic::VicManager::new();
+
+        self.uart1.reset();
+        self.uart2.reset();
+        if let Some(uart3) = &mut self.uart3_hack {
+            uart3.reset();
+        }
+        self.update_clocks();
+    }
+
+    /// Notify any mode-aware devices of the CPU mode that subsequent memory
+    /// accesses will be made from.
+    fn set_cpu_mode(&mut self, mode: ArmMode) {
+        let privileged = !matches!(mode, ArmMode::User);
+        self.vicmgr.set_privileged(privileged);
+    }
+
+    /// Run `f` with memory accesses flagged as coming from the debugger, such
+    /// that inspecting registers doesn't disturb the guest.
+    pub fn debugger_access<T>(&mut self, f: impl FnOnce(&mut Ts7200Bus) -> T) -> T {
+        self.vicmgr.set_debugger_access(true);
+        let res = f(self);
+        self.vicmgr.set_debugger_access(false);
+        res
+    }
+
+    /// Propagate the Syscon's clock configuration to the devices it drives.
+    ///
+    /// NOTE: the emulated CPU isn't cycle accurate, so the PLL derived clocks
+    /// (FCLK / HCLK / PCLK) are only used for informational purposes. None of
+    /// the emulated peripherals depend on PCLK's frequency: the timers run off
+    /// fixed 508 kHz / 2 kHz clocks derived from XTALI, and have no clock
+    /// enables in the Syscon, so they're always running.
+    fn update_clocks(&mut self) {
+        self.uart1
+            .set_clock_enabled(self.syscon.uart_enabled(1).unwrap_or(true));
+        self.uart2
+            .set_clock_enabled(self.syscon.uart_enabled(2).unwrap_or(true));
+        for channel in 0..devices::dma::NUM_M2P_CHANNELS {
+            let enabled = self.syscon.dma_m2p_enabled(channel);
+            self.dma.set_clock_enabled(channel, enabled);
+        }
+
+        let uartclk_hz = self
+            .uartclk_override
+            .unwrap_or_else(|| self.syscon.uartclk_hz());
+        self.uart1.set_uartclk_hz(uartclk_hz);
+        self.uart2.set_uartclk_hz(uartclk_hz);
+        if let Some(uart3) = &mut self.uart3_hack {
+            uart3.set_uartclk_hz(uartclk_hz);
+        }
+    }
+
+    /// Override the UARTCLK frequency derived from the Syscon's PwrCnt
+    /// register.
+    pub fn set_uartclk_override(&mut self, hz: u64) {
+        self.uartclk_override = Some(hz);
+        self.update_clocks();
+    }
+
+    fn dma_uart(&mut self, port: devices::dma::Port) -> Option<&mut devices::Uart> {
+        use devices::dma::Port;
+        match port {
+            Port::Uart1 => Some(&mut self.uart1),
+            Port::Uart2 => Some(&mut self.uart2),
+            Port::Uart3 => self.uart3_hack.as_mut(),
+            _ => None,
+        }
+    }
+
+    /// Carry out a single byte of any DMA transfers the channels' peripherals
+    /// are currently requesting.
+    fn service_dma(&mut self) {
+        use devices::dma::Direction;
+
+        for &t in self.dma.transfers().iter().flatten() {
+            let res = match t.direction {
+                Direction::MemToPeripheral => {
+                    match self.dma_uart(t.port) {
+                        Some(uart) if uart.dma_tx_request() => {}
+                        _ => continue,
+                    }
+                    self.r8(t.addr).and_then(|b| {
+                        let uart = self.dma_uart(t.port).unwrap();
+                        uart.w32(0, b as u32)
+                    })
+                }
+                Direction::PeripheralToMem => {
+                    let uart = match self.dma_uart(t.port) {
+                        Some(uart) if uart.dma_rx_request() => uart,
+                        _ => continue,
+                    };
+                    uart.r32(0).and_then(|b| self.w8(t.addr, b as u8))
+                }
+            };
+
+            match res {
+                Ok(()) => self.dma.transfer_done(t.channel),
+                Err(e) => {
+                    error!(
+                        "DMA channel {} transfer error at {:#010x?} ({:?}): {:?}",
+                        t.channel, t.addr, t.port, e
+                    );
+                    self.dma.transfer_error(t.channel);
+                }
+            }
         }
     }
 
@@ -342,6 +640,7 @@ impl Ts7200Bus {
             self.interrupt_bus.clone(),
             uart::interrupts::UART3,
         ));
+        self.update_clocks();
     }
 }
 
@@ -401,10 +700,12 @@ macro_rules! ts7200_mmap {
 ts7200_mmap! {
     // TODO: fill out more of the memory map
     0x0000_0000..=0x01ff_ffff => sdram,
+    0x8000_0000..=0x8000_ffff => dma,
     0x800b_0000..=0x800c_ffff => vicmgr,
     0x8081_0000..=0x8081_001f => timer1,
     0x8081_0020..=0x8081_003f => timer2,
     0x8081_0080..=0x8081_009f => timer3,
+    0x808b_0000..=0x808b_ffff => irda,
     0x808c_0000..=0x808c_ffff => uart1,
     0x808d_0000..=0x808d_ffff => uart2,
     0x808e_0000..=0x808e_ffff => uart3_hack,